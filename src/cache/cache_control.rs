@@ -2,6 +2,9 @@ use http::{Request, Response};
 use hyper::Body;
 use anyhow::{Result, Error};
 
+use crate::proxy_response::response::ProxyResponse;
+use crate::proxy::headers::ProxyHeaders;
+
 #[derive(Clone, Debug, Default)]
 pub struct CacheControlRequest {
     max_age: Option<String>,
@@ -9,7 +12,6 @@ pub struct CacheControlRequest {
     min_fresh: Option<String>,
     no_cache: Option<String>,
     no_store: Option<String>,
-    no_transform: Option<String>,
     only_if_cached: Option<String>,
     stale_if_error: Option<String>,
 }
@@ -20,10 +22,8 @@ pub struct CacheControlResponse  {
     s_maxage: Option<String>,
     no_cache: Option<String>,
     no_store: Option<String>,
-    no_transform: Option<String>,
     must_revalidate: Option<String>,
     proxy_revalidate: Option<String>,
-    must_understand: Option<String>,
     private: Option<String>,
     public: Option<String>,
     immutable: Option<String>,
@@ -36,19 +36,9 @@ impl TryFrom<&Request<Body>> for CacheControlRequest {
     type Error = Error;
 
     fn try_from(req: &Request<Body>) -> Result<Self, Self::Error> {
-        let headers = req.headers();
-        let cache_control = headers.get("cache-control").map(|it| it.to_str().unwrap_or(""));
-        if let Some(content) = cache_control {
-            let cache_control_content: Vec<&str> = content.split(',').map(|word| word.trim()).collect();
-            let max_age = cache_control_content.iter().find(|&elem| elem.starts_with("max-age")).map(|content| content.to_lowercase());
-            let max_stale = cache_control_content.iter().find(|&elem| elem.starts_with("max-stale")).map(|content| content.to_lowercase());
-            let min_fresh = cache_control_content.iter().find(|&elem| elem.starts_with("min-fresh")).map(|content| content.to_lowercase());
-            let no_cache = cache_control_content.iter().find(|&elem| elem.starts_with("no-cache")).map(|content| content.to_lowercase());
-            let no_store = cache_control_content.iter().find(|&elem| elem.starts_with("no-store")).map(|content| content.to_lowercase());
-            let no_transform = cache_control_content.iter().find(|&elem| elem.starts_with("no-transform")).map(|content| content.to_lowercase());
-            let only_if_cached = cache_control_content.iter().find(|&elem| elem.starts_with("only-if-cached")).map(|content| content.to_lowercase());
-            let stale_if_error = cache_control_content.iter().find(|&elem| elem.starts_with("stale-if-error")).map(|content| content.to_lowercase());
-            Ok(CacheControlRequest { max_age, max_stale, min_fresh, no_cache, no_store, no_transform, only_if_cached, stale_if_error })
+        let headers = ProxyHeaders::from(req.headers());
+        if let Some(content) = headers.get_combined("cache-control") {
+            Ok(CacheControlRequest::from(content.as_str()))
         } else {
             tracing::debug!("Error: No cache-control header");
             anyhow::bail!("No cache-control header");
@@ -56,31 +46,149 @@ impl TryFrom<&Request<Body>> for CacheControlRequest {
     }
 }
 
+impl From<&str> for CacheControlRequest {
+    fn from(content: &str) -> Self {
+        let content = content.to_lowercase();
+        let cache_control_content: Vec<&str> = content.split(',').map(|word| word.trim()).collect();
+        let max_age = cache_control_content.iter().find(|&elem| elem.starts_with("max-age")).map(|content| content.to_string());
+        let max_stale = cache_control_content.iter().find(|&elem| elem.starts_with("max-stale")).map(|content| content.to_string());
+        let min_fresh = cache_control_content.iter().find(|&elem| elem.starts_with("min-fresh")).map(|content| content.to_string());
+        let no_cache = cache_control_content.iter().find(|&elem| elem.starts_with("no-cache")).map(|content| content.to_string());
+        let no_store = cache_control_content.iter().find(|&elem| elem.starts_with("no-store")).map(|content| content.to_string());
+        let only_if_cached = cache_control_content.iter().find(|&elem| elem.starts_with("only-if-cached")).map(|content| content.to_string());
+        let stale_if_error = cache_control_content.iter().find(|&elem| elem.starts_with("stale-if-error")).map(|content| content.to_string());
+        CacheControlRequest { max_age, max_stale, min_fresh, no_cache, no_store, only_if_cached, stale_if_error }
+    }
+}
+
 impl TryFrom<&Response<Body>> for CacheControlResponse {
     type Error = Error;
 
     fn try_from(resp: &Response<Body>) -> Result<Self, Self::Error> {
-        let headers = resp.headers();
-        let cache_control = headers.get("cache-control").map(|it| it.to_str().unwrap_or(""));
-        if let Some(content) = cache_control {
-            let cache_control_content: Vec<&str> = content.split(',').map(|word| word.trim()).collect();
-            let max_age = cache_control_content.iter().find(|&elem| elem.starts_with("max-age")).map(|content| content.to_lowercase());
-            let s_maxage = cache_control_content.iter().find(|&elem| elem.starts_with("s-maxage")).map(|content| content.to_lowercase());
-            let no_cache = cache_control_content.iter().find(|&elem| elem.starts_with("no-cache")).map(|content| content.to_lowercase());
-            let no_store = cache_control_content.iter().find(|&elem| elem.starts_with("no-store")).map(|content| content.to_lowercase());
-            let no_transform = cache_control_content.iter().find(|&elem| elem.starts_with("no-transform")).map(|content| content.to_lowercase());
-            let must_revalidate = cache_control_content.iter().find(|&elem| elem.starts_with("must-revalidate")).map(|content| content.to_lowercase());
-            let proxy_revalidate = cache_control_content.iter().find(|&elem| elem.starts_with("proxy-revalidate")).map(|content| content.to_lowercase());
-            let must_understand = cache_control_content.iter().find(|&elem| elem.starts_with("must-understand")).map(|content| content.to_lowercase());
-            let private = cache_control_content.iter().find(|&elem| elem.starts_with("private")).map(|content| content.to_lowercase());
-            let public = cache_control_content.iter().find(|&elem| elem.starts_with("public")).map(|content| content.to_lowercase());
-            let immutable = cache_control_content.iter().find(|&elem| elem.starts_with("immutable")).map(|content| content.to_lowercase());
-            let stale_while_revalidate = cache_control_content.iter().find(|&elem| elem.starts_with("stale-while-revalidate")).map(|content| content.to_lowercase());
-            let stale_if_error = cache_control_content.iter().find(|&elem| elem.starts_with("stale-if-error")).map(|content| content.to_lowercase());
-            Ok(CacheControlResponse { max_age, s_maxage, no_cache, no_store, no_transform, must_revalidate, proxy_revalidate, must_understand, private, public, immutable, stale_while_revalidate, stale_if_error })
+        let headers = ProxyHeaders::from(resp.headers());
+        if let Some(content) = headers.get_combined("cache-control") {
+            Ok(CacheControlResponse::from(content.as_str()))
+        } else {
+            tracing::debug!("Error: No cache-control header");
+            anyhow::bail!("No cache-control header");
+        }
+    }
+}
+
+impl<'a> TryFrom<&ProxyResponse<'a>> for CacheControlResponse {
+    type Error = Error;
+
+    fn try_from(resp: &ProxyResponse<'a>) -> Result<Self, Self::Error> {
+        if let Some(content) = resp.headers.get_combined("cache-control") {
+            Ok(CacheControlResponse::from(content.as_str()))
         } else {
             tracing::debug!("Error: No cache-control header");
             anyhow::bail!("No cache-control header");
         }
     }
 }
+
+impl From<&str> for CacheControlResponse {
+    fn from(content: &str) -> Self {
+        let content = content.to_lowercase();
+        let cache_control_content: Vec<&str> = content.split(',').map(|word| word.trim()).collect();
+        let max_age = cache_control_content.iter().find(|&elem| elem.starts_with("max-age")).map(|content| content.to_string());
+        let s_maxage = cache_control_content.iter().find(|&elem| elem.starts_with("s-maxage")).map(|content| content.to_string());
+        let no_cache = cache_control_content.iter().find(|&elem| elem.starts_with("no-cache")).map(|content| content.to_string());
+        let no_store = cache_control_content.iter().find(|&elem| elem.starts_with("no-store")).map(|content| content.to_string());
+        let must_revalidate = cache_control_content.iter().find(|&elem| elem.starts_with("must-revalidate")).map(|content| content.to_string());
+        let proxy_revalidate = cache_control_content.iter().find(|&elem| elem.starts_with("proxy-revalidate")).map(|content| content.to_string());
+        let private = cache_control_content.iter().find(|&elem| elem.starts_with("private")).map(|content| content.to_string());
+        let public = cache_control_content.iter().find(|&elem| elem.starts_with("public")).map(|content| content.to_string());
+        let immutable = cache_control_content.iter().find(|&elem| elem.starts_with("immutable")).map(|content| content.to_string());
+        let stale_while_revalidate = cache_control_content.iter().find(|&elem| elem.starts_with("stale-while-revalidate")).map(|content| content.to_string());
+        let stale_if_error = cache_control_content.iter().find(|&elem| elem.starts_with("stale-if-error")).map(|content| content.to_string());
+        CacheControlResponse { max_age, s_maxage, no_cache, no_store, must_revalidate, proxy_revalidate, private, public, immutable, stale_while_revalidate, stale_if_error }
+    }
+}
+
+impl CacheControlRequest {
+    pub fn get_max_age(&self) -> Option<u64> {
+        get_directive_seconds(&self.max_age)
+    }
+
+    // max-stale without a value means any staleness is accepted
+    pub fn get_max_stale(&self) -> Option<u64> {
+        self.max_stale.as_ref().map(|_| get_directive_seconds(&self.max_stale).unwrap_or(u64::MAX))
+    }
+
+    pub fn get_min_fresh(&self) -> Option<u64> {
+        get_directive_seconds(&self.min_fresh)
+    }
+
+    pub fn is_no_cache(&self) -> bool {
+        self.no_cache.is_some()
+    }
+
+    pub fn is_no_store(&self) -> bool {
+        self.no_store.is_some()
+    }
+
+    pub fn is_only_if_cached(&self) -> bool {
+        self.only_if_cached.is_some()
+    }
+
+    pub fn get_stale_if_error(&self) -> Option<u64> {
+        get_directive_seconds(&self.stale_if_error)
+    }
+}
+
+impl CacheControlResponse {
+    pub fn get_max_age(&self) -> Option<u64> {
+        get_directive_seconds(&self.max_age)
+    }
+
+    pub fn get_s_maxage(&self) -> Option<u64> {
+        get_directive_seconds(&self.s_maxage)
+    }
+
+    pub fn get_stale_while_revalidate(&self) -> Option<u64> {
+        get_directive_seconds(&self.stale_while_revalidate)
+    }
+
+    pub fn get_stale_if_error(&self) -> Option<u64> {
+        get_directive_seconds(&self.stale_if_error)
+    }
+
+    pub fn is_no_cache(&self) -> bool {
+        self.no_cache.is_some()
+    }
+
+    pub fn is_no_store(&self) -> bool {
+        self.no_store.is_some()
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private.is_some()
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.public.is_some()
+    }
+
+    pub fn is_immutable(&self) -> bool {
+        self.immutable.is_some()
+    }
+
+    // proxy-revalidate is must-revalidate for shared caches like us
+    pub fn is_must_revalidate(&self) -> bool {
+        self.must_revalidate.is_some() || self.proxy_revalidate.is_some()
+    }
+
+    // must-revalidate alone, proxy-revalidate doesn't allow sharing responses to authorized requests (RFC 9111 3.5)
+    pub fn has_must_revalidate(&self) -> bool {
+        self.must_revalidate.is_some()
+    }
+}
+
+// Directives with an argument are stored as "name=value", value being a number of seconds (RFC 9111 1.2.2)
+fn get_directive_seconds(directive: &Option<String>) -> Option<u64> {
+    directive.as_ref()
+        .and_then(|content| content.split_once('='))
+        .and_then(|(_, value)| value.trim().trim_matches('"').parse::<u64>().ok())
+}
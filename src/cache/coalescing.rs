@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+// Collapses concurrent origin requests for the same cache key within this instance
+#[derive(Clone, Default)]
+pub struct RequestCoalescer {
+    in_flight: Arc<Mutex<HashMap<String, watch::Receiver<()>>>>,
+}
+
+pub enum Flight {
+    // First request for the key, it goes to the origin and releases the others once done
+    Leader(FlightGuard),
+    // Another request is already fetching the key
+    Follower(watch::Receiver<()>),
+}
+
+// Releases the followers when dropped, whether the leader stored the response or not
+pub struct FlightGuard {
+    key: String,
+    in_flight: Arc<Mutex<HashMap<String, watch::Receiver<()>>>>,
+    _sender: watch::Sender<()>,
+}
+
+impl RequestCoalescer {
+    pub fn join(&self, key: &str) -> Flight {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(receiver) = in_flight.get(key) {
+            return Flight::Follower(receiver.clone());
+        }
+        let (sender, receiver) = watch::channel(());
+        in_flight.insert(key.to_string(), receiver);
+        Flight::Leader(FlightGuard { key: key.to_string(), in_flight: self.in_flight.clone(), _sender: sender })
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        in_flight.remove(&self.key);
+    }
+}
+
+// Wait for the leader to be done, returns false if it took longer than the timeout
+pub async fn wait_for_leader(mut receiver: watch::Receiver<()>, timeout: Duration) -> bool {
+    // The leader never sends, changed() returns once its sender is dropped
+    tokio::time::timeout(timeout, receiver.changed()).await.is_ok()
+}
@@ -0,0 +1,92 @@
+use http::Request;
+use hyper::Body;
+
+use crate::proxy_response::response::ProxyResponse;
+use crate::proxy::headers::from_header_value;
+
+// Validators sent by the client to check its own copy against ours (RFC 9110 13.1)
+#[derive(Clone, Debug, Default)]
+pub struct ConditionalRequest {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+impl From<&Request<Body>> for ConditionalRequest {
+    fn from(req: &Request<Body>) -> Self {
+        let headers = req.headers();
+        let if_none_match = headers.get("if-none-match").map(from_header_value);
+        let if_modified_since = headers.get("if-modified-since").map(from_header_value);
+        ConditionalRequest { if_none_match, if_modified_since }
+    }
+}
+
+impl ConditionalRequest {
+    // Whether the client copy is still valid and a 304 can be sent instead of the stored response (RFC 9110 13.2.2)
+    pub fn is_not_modified(&self, resp: &ProxyResponse) -> bool {
+        if resp.status != 200 {
+            return false;
+        }
+        // If-Modified-Since is ignored when If-None-Match is present
+        if let Some(if_none_match) = self.if_none_match.as_ref() {
+            return resp.headers.get("etag").map(|etag| is_etag_matching(if_none_match, etag)).unwrap_or(false);
+        }
+        if let Some(if_modified_since) = self.if_modified_since.as_ref() {
+            let if_modified_since = match httpdate::parse_http_date(if_modified_since) {
+                Ok(date) => date,
+                Err(_) => return false,
+            };
+            let last_modified = resp.headers.get("last-modified")
+                .or_else(|| resp.headers.get("date"))
+                .and_then(|date| httpdate::parse_http_date(date).ok());
+            return last_modified.map(|last_modified| last_modified <= if_modified_since).unwrap_or(false);
+        }
+        false
+    }
+}
+
+// Weak comparison of an If-None-Match list against the stored ETag (RFC 9110 8.8.3.2)
+fn is_etag_matching(if_none_match: &str, etag: &str) -> bool {
+    let if_none_match = if_none_match.trim();
+    if if_none_match == "*" {
+        return true;
+    }
+    let etag = etag.trim().trim_start_matches("W/");
+    if_none_match.split(',').map(|tag| tag.trim().trim_start_matches("W/")).any(|tag| tag == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::response::Builder;
+
+    use super::*;
+
+    fn response(status: u16, pairs: &[(&str, &str)]) -> ProxyResponse<'static> {
+        let builder = pairs.iter().fold(Builder::new().status(status), |builder, (name, value)| builder.header(*name, *value));
+        let (parts, _) = builder.body(()).unwrap().into_parts();
+        ProxyResponse::from_parts(&parts).into_owned()
+    }
+
+    fn conditional(pairs: &[(&str, &str)]) -> ConditionalRequest {
+        let builder = pairs.iter().fold(Request::builder(), |builder, (name, value)| builder.header(*name, *value));
+        ConditionalRequest::from(&builder.body(Body::empty()).unwrap())
+    }
+
+    #[test]
+    fn matches_etags_weakly() {
+        let resp = response(200, &[("etag", "W/\"v1\"")]);
+        assert!(conditional(&[("if-none-match", "\"v0\", \"v1\"")]).is_not_modified(&resp));
+        assert!(conditional(&[("if-none-match", "*")]).is_not_modified(&resp));
+        assert!(!conditional(&[("if-none-match", "\"v2\"")]).is_not_modified(&resp));
+        assert!(!conditional(&[("if-none-match", "\"v1\"")]).is_not_modified(&response(404, &[("etag", "\"v1\"")])));
+    }
+
+    #[test]
+    fn compares_modification_dates() {
+        let resp = response(200, &[("last-modified", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert!(conditional(&[("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]).is_not_modified(&resp));
+        assert!(!conditional(&[("if-modified-since", "Sat, 05 Nov 1994 08:49:37 GMT")]).is_not_modified(&resp));
+        assert!(!conditional(&[("if-modified-since", "not a date")]).is_not_modified(&resp));
+        // If-None-Match takes precedence
+        assert!(!conditional(&[("if-none-match", "\"v1\""), ("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]).is_not_modified(&resp));
+    }
+}
@@ -0,0 +1,212 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::cache_control::{CacheControlRequest, CacheControlResponse};
+use crate::proxy::headers::ProxyHeaders;
+
+// What we need to know about a stored response to compute its age later on (RFC 9111 4.2.3)
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct Freshness {
+    pub stored_at: u64,
+    pub initial_age: u64,
+    pub lifetime: u64,
+}
+
+impl Freshness {
+    pub fn new(cache_control: &CacheControlResponse, headers: &ProxyHeaders, default_ttl: u64) -> Self {
+        // no-cache responses can be stored but must be revalidated before each reuse
+        let lifetime = if cache_control.is_no_cache() {
+            0
+        } else {
+            get_freshness_lifetime(cache_control, headers).unwrap_or(default_ttl)
+        };
+        Freshness { stored_at: now(), initial_age: get_age(headers), lifetime }
+    }
+
+    pub fn get_current_age(&self) -> u64 {
+        self.initial_age + now().saturating_sub(self.stored_at)
+    }
+
+    // Number of seconds the response can still be served fresh from the cache
+    pub fn get_remaining(&self) -> u64 {
+        self.lifetime.saturating_sub(self.get_current_age())
+    }
+
+    pub fn get_staleness(&self) -> u64 {
+        self.get_current_age().saturating_sub(self.lifetime)
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.lifetime > self.get_current_age()
+    }
+
+    // Whether the stored response satisfies the request directives without going to the origin (RFC 9111 5.2.1)
+    pub fn is_acceptable(&self, request_cc: &CacheControlRequest, response_cc: &CacheControlResponse) -> bool {
+        // A fresh immutable response won't change, reloads (no-cache, max-age=0) don't need the origin (RFC 8246)
+        let reload_allowed = response_cc.is_immutable() && self.is_fresh();
+        if request_cc.is_no_cache() && !reload_allowed {
+            return false;
+        }
+        if request_cc.get_max_age().map(|max_age| self.get_current_age() > max_age).unwrap_or(false) && !reload_allowed {
+            return false;
+        }
+        if request_cc.get_min_fresh().map(|min_fresh| self.get_remaining() < min_fresh).unwrap_or(false) {
+            return false;
+        }
+        if self.is_fresh() {
+            return true;
+        }
+        // must-revalidate forbids serving stale content even if the client accepts it
+        if response_cc.is_must_revalidate() || response_cc.is_no_cache() {
+            return false;
+        }
+        request_cc.get_max_stale().map(|max_stale| self.get_staleness() <= max_stale).unwrap_or(false)
+    }
+
+    // Whether the stale response can be served while it is refreshed in the background (RFC 5861 3)
+    pub fn is_stale_while_revalidate(&self, request_cc: &CacheControlRequest, response_cc: &CacheControlResponse) -> bool {
+        if request_cc.is_no_cache() || response_cc.is_must_revalidate() || response_cc.is_no_cache() {
+            return false;
+        }
+        response_cc.get_stale_while_revalidate().map(|swr| self.get_staleness() <= swr).unwrap_or(false)
+    }
+
+    // Whether the stale response can be served when the origin fails (RFC 5861 4), the client directive wins over the origin one
+    pub fn is_stale_if_error(&self, request_cc: &CacheControlRequest, response_cc: &CacheControlResponse, default_stale_if_error: u64) -> bool {
+        if response_cc.is_must_revalidate() || response_cc.is_no_cache() {
+            return false;
+        }
+        let stale_if_error = request_cc.get_stale_if_error()
+            .or_else(|| response_cc.get_stale_if_error())
+            .unwrap_or(default_stale_if_error);
+        self.get_staleness() <= stale_if_error
+    }
+}
+
+// Freshness lifetime as defined in RFC 9111 4.2.1: s-maxage, then max-age, then Expires - Date
+pub fn get_freshness_lifetime(cache_control: &CacheControlResponse, headers: &ProxyHeaders) -> Option<u64> {
+    if let Some(s_maxage) = cache_control.get_s_maxage() {
+        return Some(s_maxage);
+    }
+    if let Some(max_age) = cache_control.get_max_age() {
+        return Some(max_age);
+    }
+    let expires = headers.get("expires")?;
+    // An invalid Expires value (e.g. "0") means the response is already expired
+    let expires = match httpdate::parse_http_date(expires) {
+        Ok(expires) => expires,
+        Err(_) => return Some(0),
+    };
+    let date = headers.get("date")
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+        .unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(date).map(|lifetime| lifetime.as_secs()).unwrap_or(0))
+}
+
+// Age already accumulated by the response upstream of us (RFC 9111 5.1)
+pub fn get_age(headers: &ProxyHeaders) -> u64 {
+    headers.get("age").and_then(|age| age.trim().parse::<u64>().ok()).unwrap_or(0)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_lifetime_by_precedence() {
+        let expires = ProxyHeaders::from_pairs(&[("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("expires", "Sun, 06 Nov 1994 09:49:37 GMT")]);
+        assert_eq!(get_freshness_lifetime(&CacheControlResponse::from("max-age=60, s-maxage=120"), &expires), Some(120));
+        assert_eq!(get_freshness_lifetime(&CacheControlResponse::from("max-age=60"), &expires), Some(60));
+        assert_eq!(get_freshness_lifetime(&CacheControlResponse::from(""), &expires), Some(3600));
+        assert_eq!(get_freshness_lifetime(&CacheControlResponse::from(""), &ProxyHeaders::from_pairs(&[])), None);
+    }
+
+    #[test]
+    fn invalid_expires_is_expired() {
+        assert_eq!(get_freshness_lifetime(&CacheControlResponse::from(""), &ProxyHeaders::from_pairs(&[("expires", "0")])), Some(0));
+    }
+
+    #[test]
+    fn age_counts_as_initial_age() {
+        let freshness = Freshness::new(&CacheControlResponse::from("max-age=100"), &ProxyHeaders::from_pairs(&[("age", "30")]), 5);
+        assert_eq!(freshness.initial_age, 30);
+        assert!(freshness.is_fresh());
+        assert!(freshness.get_remaining() <= 70);
+
+        let freshness = Freshness::new(&CacheControlResponse::from("max-age=100"), &ProxyHeaders::from_pairs(&[("age", "150")]), 5);
+        assert!(!freshness.is_fresh());
+        assert!(freshness.get_staleness() >= 50);
+    }
+
+    #[test]
+    fn honors_request_directives() {
+        let fresh = Freshness { stored_at: now(), initial_age: 10, lifetime: 100 };
+        let response_cc = CacheControlResponse::from("max-age=100");
+        assert!(fresh.is_acceptable(&CacheControlRequest::default(), &response_cc));
+        assert!(!fresh.is_acceptable(&CacheControlRequest::from("no-cache"), &response_cc));
+        assert!(!fresh.is_acceptable(&CacheControlRequest::from("max-age=5"), &response_cc));
+        assert!(fresh.is_acceptable(&CacheControlRequest::from("max-age=30"), &response_cc));
+        assert!(fresh.is_acceptable(&CacheControlRequest::from("min-fresh=50"), &response_cc));
+        assert!(!fresh.is_acceptable(&CacheControlRequest::from("min-fresh=95"), &response_cc));
+    }
+
+    #[test]
+    fn max_stale_accepts_stale_unless_must_revalidate() {
+        let stale = Freshness { stored_at: now(), initial_age: 150, lifetime: 100 };
+        let response_cc = CacheControlResponse::from("max-age=100");
+        assert!(!stale.is_acceptable(&CacheControlRequest::default(), &response_cc));
+        assert!(stale.is_acceptable(&CacheControlRequest::from("max-stale=60"), &response_cc));
+        assert!(!stale.is_acceptable(&CacheControlRequest::from("max-stale=10"), &response_cc));
+        // Without a value any staleness is accepted
+        assert!(stale.is_acceptable(&CacheControlRequest::from("max-stale"), &response_cc));
+        assert!(!stale.is_acceptable(&CacheControlRequest::from("max-stale"), &CacheControlResponse::from("max-age=100, must-revalidate")));
+        assert!(!stale.is_acceptable(&CacheControlRequest::from("max-stale"), &CacheControlResponse::from("max-age=100, proxy-revalidate")));
+    }
+
+    #[test]
+    fn serves_stale_while_revalidating_within_window() {
+        let stale = Freshness { stored_at: now(), initial_age: 130, lifetime: 100 };
+        let request_cc = CacheControlRequest::default();
+        assert!(stale.is_stale_while_revalidate(&request_cc, &CacheControlResponse::from("max-age=100, stale-while-revalidate=60")));
+        assert!(!stale.is_stale_while_revalidate(&request_cc, &CacheControlResponse::from("max-age=100, stale-while-revalidate=10")));
+        assert!(!stale.is_stale_while_revalidate(&request_cc, &CacheControlResponse::from("max-age=100")));
+        assert!(!stale.is_stale_while_revalidate(&request_cc, &CacheControlResponse::from("max-age=100, stale-while-revalidate=60, must-revalidate")));
+        assert!(!stale.is_stale_while_revalidate(&request_cc, &CacheControlResponse::from("no-cache, stale-while-revalidate=60")));
+        assert!(!stale.is_stale_while_revalidate(&CacheControlRequest::from("no-cache"), &CacheControlResponse::from("max-age=100, stale-while-revalidate=60")));
+    }
+
+    #[test]
+    fn stale_if_error_prefers_request_then_response_then_default() {
+        let stale = Freshness { stored_at: now(), initial_age: 130, lifetime: 100 };
+        let response_cc = CacheControlResponse::from("max-age=100, stale-if-error=10");
+        // The request directive wins, even when it is shorter
+        assert!(stale.is_stale_if_error(&CacheControlRequest::from("stale-if-error=60"), &response_cc, 0));
+        assert!(!stale.is_stale_if_error(&CacheControlRequest::from("stale-if-error=5"), &CacheControlResponse::from("max-age=100, stale-if-error=60"), 0));
+        // Then the response one, over the configured default
+        assert!(!stale.is_stale_if_error(&CacheControlRequest::default(), &response_cc, 60));
+        assert!(stale.is_stale_if_error(&CacheControlRequest::default(), &CacheControlResponse::from("max-age=100, stale-if-error=60"), 0));
+        // Then CACHER_STALE_IF_ERROR
+        assert!(stale.is_stale_if_error(&CacheControlRequest::default(), &CacheControlResponse::from("max-age=100"), 60));
+        assert!(!stale.is_stale_if_error(&CacheControlRequest::default(), &CacheControlResponse::from("max-age=100"), 10));
+        // must-revalidate and no-cache responses are never served stale
+        assert!(!stale.is_stale_if_error(&CacheControlRequest::from("stale-if-error=60"), &CacheControlResponse::from("max-age=100, must-revalidate"), 60));
+        assert!(!stale.is_stale_if_error(&CacheControlRequest::from("stale-if-error=60"), &CacheControlResponse::from("no-cache"), 60));
+    }
+
+    #[test]
+    fn immutable_ignores_reloads_while_fresh() {
+        let fresh = Freshness { stored_at: now(), initial_age: 10, lifetime: 100 };
+        let reload = CacheControlRequest::from("no-cache");
+        assert!(!fresh.is_acceptable(&reload, &CacheControlResponse::from("max-age=100")));
+        assert!(fresh.is_acceptable(&reload, &CacheControlResponse::from("max-age=100, immutable")));
+        assert!(fresh.is_acceptable(&CacheControlRequest::from("max-age=0"), &CacheControlResponse::from("max-age=100, immutable")));
+
+        let stale = Freshness { stored_at: now(), initial_age: 200, lifetime: 100 };
+        assert!(!stale.is_acceptable(&reload, &CacheControlResponse::from("max-age=100, immutable")));
+    }
+}
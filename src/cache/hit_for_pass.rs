@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Markers above this count trigger a sweep of the expired ones
+const SWEEP_THRESHOLD: usize = 1024;
+
+// Keys whose last origin response couldn't be stored, their requests go to the origin without waiting for each other
+#[derive(Clone, Default)]
+pub struct HitForPass {
+    marked: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl HitForPass {
+    pub fn mark(&self, key: &str, ttl: Duration) {
+        let mut marked = self.marked.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Instant::now();
+        if marked.len() >= SWEEP_THRESHOLD {
+            marked.retain(|_, expires_at| *expires_at > now);
+        }
+        marked.insert(key.to_string(), now + ttl);
+    }
+
+    // The response became storable again
+    pub fn unmark(&self, key: &str) {
+        let mut marked = self.marked.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        marked.remove(key);
+    }
+
+    pub fn is_marked(&self, key: &str) -> bool {
+        let mut marked = self.marked.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match marked.get(key) {
+            Some(expires_at) if *expires_at > Instant::now() => true,
+            Some(_) => {
+                marked.remove(key);
+                false
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markers_expire_and_can_be_removed() {
+        let hit_for_pass = HitForPass::default();
+        hit_for_pass.mark("a", Duration::from_secs(60));
+        hit_for_pass.mark("b", Duration::ZERO);
+        assert!(hit_for_pass.is_marked("a"));
+        assert!(!hit_for_pass.is_marked("b"));
+        assert!(!hit_for_pass.is_marked("c"));
+        hit_for_pass.unmark("a");
+        assert!(!hit_for_pass.is_marked("a"));
+    }
+}
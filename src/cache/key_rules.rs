@@ -0,0 +1,165 @@
+use serde::Deserialize;
+
+use crate::proxy::headers::ProxyHeaders;
+
+// Which query params go into the key, names ending with '*' match every param starting with the rest
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryRule {
+    #[default]
+    All,
+    Ignore,
+    Include(Vec<String>),
+    Exclude(Vec<String>),
+}
+
+// What goes into the key of the requests whose path matches, e.g.
+// {"path": "/products/*", "query": {"include": ["id"]}, "sort_query": true, "headers": ["x-country"], "cookies": ["currency"], "host": false}
+#[derive(Clone, Debug, Deserialize)]
+pub struct KeyRule {
+    // '*' matches any run of characters, '/' included
+    path: String,
+    #[serde(default)]
+    query: QueryRule,
+    #[serde(default)]
+    sort_query: bool,
+    #[serde(default)]
+    headers: Vec<String>,
+    #[serde(default)]
+    cookies: Vec<String>,
+    #[serde(default = "include_host")]
+    host: bool,
+}
+
+fn include_host() -> bool {
+    true
+}
+
+impl Default for KeyRule {
+    fn default() -> Self {
+        KeyRule { path: "*".to_string(), query: QueryRule::All, sort_query: false, headers: Vec::new(), cookies: Vec::new(), host: true }
+    }
+}
+
+impl KeyRule {
+    pub fn matches(&self, path: &str) -> bool {
+        matches_glob(&self.path, path)
+    }
+
+    pub fn get_url(&self, scheme: &str, authority: &str, path_and_query: &str) -> String {
+        let (path, query) = path_and_query.split_once('?').unwrap_or((path_and_query, ""));
+        let query = self.get_query(query);
+        let path_and_query = if query.is_empty() { path.to_string() } else { format!("{}?{}", path, query) };
+        if self.host {
+            format!("{}://{}{}", scheme, authority, path_and_query)
+        } else {
+            path_and_query
+        }
+    }
+
+    // Query params that go into the key
+    pub fn get_query(&self, query: &str) -> String {
+        let mut params: Vec<&str> = query.split('&').filter(|param| !param.is_empty()).filter(|param| self.keeps(param)).collect();
+        if self.sort_query {
+            params.sort_by_key(|param| param.split('=').next().unwrap_or_default());
+        }
+        params.join("&")
+    }
+
+    // Named headers and cookies, in the order of the rule
+    pub fn get_fields(&self, request_headers: &ProxyHeaders) -> Vec<(String, Option<String>)> {
+        let headers = self.headers.iter().map(|header| {
+            let header = header.to_ascii_lowercase();
+            let value = request_headers.get_combined(&header);
+            (format!("h:{}", header), value)
+        });
+        let cookies = self.cookies.iter().map(|cookie| (format!("c:{}", cookie), get_cookie(request_headers, cookie)));
+        headers.chain(cookies).collect()
+    }
+
+    fn keeps(&self, param: &str) -> bool {
+        let name = param.split('=').next().unwrap_or_default();
+        match &self.query {
+            QueryRule::All => true,
+            QueryRule::Ignore => false,
+            QueryRule::Include(names) => names.iter().any(|pattern| matches_name(pattern, name)),
+            QueryRule::Exclude(names) => !names.iter().any(|pattern| matches_name(pattern, name)),
+        }
+    }
+}
+
+fn matches_name(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+fn matches_glob(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match path.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    // Without '*' the whole path must match
+    if parts.is_empty() {
+        return rest.is_empty();
+    }
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+// Cookie: a=1; b=2 (RFC 6265 5.4), clients may send several Cookie fields
+fn get_cookie(request_headers: &ProxyHeaders, name: &str) -> Option<String> {
+    request_headers.get_all("cookie")
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderMap;
+
+    use super::*;
+
+    #[test]
+    fn matches_paths() {
+        assert!(matches_glob("*", "/anything"));
+        assert!(matches_glob("/products/*", "/products/42/reviews"));
+        assert!(matches_glob("/*/edit", "/posts/1/edit"));
+        assert!(!matches_glob("/products/*", "/product"));
+        assert!(!matches_glob("/about", "/about/team"));
+    }
+
+    #[test]
+    fn filters_and_sorts_query() {
+        let rule: KeyRule = serde_json::from_str(r#"{"path": "*", "query": {"exclude": ["utm_*", "fbclid"]}, "sort_query": true}"#).unwrap();
+        assert_eq!(rule.get_url("http", "example.com", "/a?utm_source=x&b=2&fbclid=y&a=1"), "http://example.com/a?a=1&b=2");
+        assert_eq!(rule.get_url("http", "example.com", "/a?utm_source=x"), "http://example.com/a");
+
+        let rule: KeyRule = serde_json::from_str(r#"{"path": "*", "query": "ignore", "host": false}"#).unwrap();
+        assert_eq!(rule.get_url("http", "example.com", "/a?b=2"), "/a");
+    }
+
+    #[test]
+    fn picks_headers_and_cookies() {
+        let rule: KeyRule = serde_json::from_str(r#"{"path": "*", "headers": ["X-Country"], "cookies": ["currency"]}"#).unwrap();
+        let mut header_map = HeaderMap::new();
+        header_map.insert("x-country", "fr".parse().unwrap());
+        header_map.insert("cookie", "session=abc; currency=EUR".parse().unwrap());
+        let fields = rule.get_fields(&ProxyHeaders::from(&header_map));
+        assert_eq!(fields, vec![("h:x-country".to_string(), Some("fr".to_string())), ("c:currency".to_string(), Some("EUR".to_string()))]);
+    }
+}
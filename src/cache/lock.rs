@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use crate::store::CacheStore;
+
+// Short-lived lock shared by all cacher instances so only one of them fetches a given key from the origin
+#[derive(Debug)]
+pub struct CacheLock {
+    key: String,
+    token: String,
+}
+
+impl CacheLock {
+    pub fn new(cache_key: &str) -> Self {
+        CacheLock { key: CacheLock::get_lock_key(cache_key), token: Uuid::new_v4().to_string() }
+    }
+
+    pub fn get_lock_key(cache_key: &str) -> String {
+        format!("lock:{}", cache_key)
+    }
+
+    pub fn get_key(&self) -> &str {
+        self.key.as_str()
+    }
+
+    // Identifies the owner, the lock may have expired and been taken by another replica
+    pub fn get_token(&self) -> &str {
+        self.token.as_str()
+    }
+}
+
+// Releases the lock when dropped, including when the origin request fails before anything is stored
+pub struct LockGuard {
+    store: Arc<dyn CacheStore>,
+    lock: Option<CacheLock>,
+}
+
+impl LockGuard {
+    pub fn new(store: Arc<dyn CacheStore>, lock: CacheLock) -> Self {
+        LockGuard { store, lock: Some(lock) }
+    }
+}
+
+impl Drop for LockGuard {
+    // Drop can't wait, the release itself happens in the background
+    fn drop(&mut self) {
+        if let Some(lock) = self.lock.take() {
+            let store = self.store.clone();
+            tokio::spawn(async move {
+                if let Err(err) = store.unlock(lock).await {
+                    tracing::warn!("Unable to release origin lock: {}", err);
+                }
+            });
+        }
+    }
+}
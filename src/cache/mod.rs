@@ -1,36 +1,124 @@
 pub mod cache_control;
+pub mod coalescing;
+pub mod conditional;
+pub mod freshness;
+pub mod hit_for_pass;
+pub mod key_rules;
+pub mod lock;
+pub mod no_vary_search;
+pub mod normalizer;
+pub mod policy;
+pub mod status;
+pub mod uri;
+pub mod vary;
 
-use std::collections::BTreeMap;
+use std::borrow::Cow;
 
 use http::{Request};
 use hyper::Body;
+use sha2::{Digest, Sha256};
 
 use crate::proxy_request::request::ProxyRequest;
+use key_rules::KeyRule;
+
+// Bumped when the layout of keys or stored entries changes, entries written by older versions are then ignored
+const KEY_SCHEMA_VERSION: u32 = 2;
 
 pub trait CacheKey {
     fn get(self) -> String;
 }
 
+// How keys are laid out: namespace, schema version, then the primary key and, for variants, the request headers
+// they are selected on. Components are escaped so different requests can't end up with the same key
+#[derive(Clone, Debug)]
+pub struct KeyFormat {
+    namespace: String,
+    // Each part is hashed separately, so a key still starts with the key of its URL
+    hash: bool,
+    // The first rule matching the path applies, the last one matches every path
+    rules: Vec<KeyRule>,
+    normalize_urls: bool,
+}
+
+impl KeyFormat {
+    pub fn new(namespace: &str, hash: bool, mut rules: Vec<KeyRule>, normalize_urls: bool) -> Self {
+        rules.push(KeyRule::default());
+        KeyFormat { namespace: namespace.to_string(), hash, rules, normalize_urls }
+    }
+
+    // Path and query the rules and the key are built from
+    pub fn normalize<'a>(&self, path_and_query: &'a str) -> Cow<'a, str> {
+        if self.normalize_urls {
+            Cow::Owned(uri::normalize(path_and_query))
+        } else {
+            Cow::Borrowed(path_and_query)
+        }
+    }
+
+    pub fn get_rule(&self, path: &str) -> &KeyRule {
+        self.rules.iter().find(|rule| rule.matches(path)).unwrap_or(&self.rules[self.rules.len() - 1])
+    }
+
+    // Method and URL, followed by the headers and cookies the route rule adds
+    pub fn get_primary_key(&self, method: &str, url: &str, fields: &[(&str, Option<&str>)]) -> String {
+        let primary = format!("{}|{}", escape(method), escape(url));
+        let key = format!("{}:v{}:{}", escape(&self.namespace), KEY_SCHEMA_VERSION, self.digest(primary));
+        if fields.is_empty() {
+            key
+        } else {
+            format!("{}|{}", key, self.digest(encode_fields(fields)))
+        }
+    }
+
+    pub fn get_variant_key(&self, primary_key: &str, headers: &[(&str, Option<&str>)]) -> String {
+        format!("{}#{}", primary_key, self.digest(encode_fields(headers)))
+    }
+
+    fn digest(&self, component: String) -> String {
+        if self.hash {
+            Sha256::digest(component.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+        } else {
+            component
+        }
+    }
+}
+
+// Fields absent from the request have no value, unlike fields sent empty
+fn encode_fields(fields: &[(&str, Option<&str>)]) -> String {
+    let encoded: Vec<String> = fields.iter().map(|(name, value)| match value {
+        Some(value) => format!("{}={}", escape(name), escape(value)),
+        None => escape(name),
+    }).collect();
+    encoded.join("&")
+}
+
+// Percent-encodes the separators, an escaped prefix of a component is still a prefix of the escaped component
+fn escape(component: &str) -> String {
+    let mut escaped = String::with_capacity(component.len());
+    for c in component.chars() {
+        if matches!(c, '%' | '|' | '#' | '&' | '=') || c.is_whitespace() || c.is_control() {
+            let mut buffer = [0; 4];
+            for byte in c.encode_utf8(&mut buffer).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
 #[derive(Debug)]
 pub struct CacheKeyNoVary {
     key: String,
 }
 
-#[derive(Debug)]
-pub struct CacheKeyWithVary<'a> {
-    uri: String,
-    vary_headers: BTreeMap<String, &'a str>, // We need ordering
-}
-impl From<&Request<Body>> for CacheKeyNoVary {
-    fn from(req: &Request<Body>) -> Self {
-        let accept_lang = req.headers().get("Accept-Language").map(|header| header.to_str().unwrap_or("")   ).unwrap_or_else(|| "");
-        let scheme = req.uri().scheme().map(|scheme| scheme.as_str()).unwrap_or("");
-        let host = req.uri().host().unwrap_or("");
-        let key = if let Some(port) = req.uri().port() {
-            format!("{}_{}://{}:{}{}{}", req.method(), scheme, host, port, req.uri(), accept_lang)
-        } else {
-            format!("{}_{}://{}{}{}", req.method(), scheme, host, req.uri(), accept_lang)
-        };
+impl CacheKeyNoVary {
+    // Responses are assumed to vary on Accept-Language when Vary isn't handled
+    pub fn new(req: &Request<Body>, key_format: &KeyFormat) -> Self {
+        let accept_lang = req.headers().get("Accept-Language").and_then(|header| header.to_str().ok());
+        let primary_key = ProxyRequest::from(req).get_primary_key(key_format);
+        let key = key_format.get_variant_key(&primary_key, &[("accept-language", accept_lang)]);
         CacheKeyNoVary { key }
     }
 }
@@ -41,50 +129,28 @@ impl CacheKey for CacheKeyNoVary {
     }
 }
 
-impl<'a> CacheKey for CacheKeyWithVary<'a> {
-    fn get(self) -> String {
-        let mut key = self.uri;
-        self.vary_headers.iter().for_each(|(_, v)| key.insert_str(key.len(), v));
-        key
-    }  
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl<'a> CacheKeyWithVary<'a> {
-    pub fn new_from_proxy(vary_content: &str, proxy_req: &'a ProxyRequest) -> Self {
-        let uri = if let Some(port) = proxy_req.port.clone() {
-            format!("{}_{}://{}:{}{}", proxy_req.method, proxy_req.scheme, proxy_req.host, port, proxy_req.uri)
-        } else {
-            format!("{}_{}://{}{}", proxy_req.method, proxy_req.scheme, proxy_req.host, proxy_req.uri)
-        };
-        let vary: Vec<&str> = vary_content.split(',').map(|word| word.trim()).collect();
-
-        let mut vary_headers = BTreeMap::new();
-        vary.iter().for_each(|&header| {
-            let header_map = proxy_req.get_headers();
-            let value = header_map.get(header).map(|it| it.as_str()).unwrap_or_else(|| "");
-            vary_headers.insert(header.to_string(), value);
-        });
-        CacheKeyWithVary { uri, vary_headers }
+    #[test]
+    fn keys_do_not_collide() {
+        let key_format = KeyFormat::new("prod", false, Vec::new(), false);
+        let primary_key = key_format.get_primary_key("GET", "http://example.com/a", &[]);
+        assert_eq!(primary_key, "prod:v2:GET|http://example.com/a");
+        assert_ne!(key_format.get_variant_key(&primary_key, &[("a", Some("b&c=d"))]), key_format.get_variant_key(&primary_key, &[("a", Some("b")), ("c", Some("d"))]));
+        assert_ne!(key_format.get_variant_key(&primary_key, &[("a", Some(""))]), key_format.get_variant_key(&primary_key, &[("a", None)]));
+        assert_ne!(key_format.get_primary_key("GET", "http://example.com/a#b", &[]), key_format.get_variant_key(&primary_key, &[("b", None)]));
+        assert_ne!(key_format.get_primary_key("GET", "http://example.com/a|c:a", &[]), key_format.get_primary_key("GET", "http://example.com/a", &[("c:a", None)]));
     }
 
-    pub fn new_from_native(vary_content: &str, req: &'a Request<Body>) -> Self {
-        let scheme = req.uri().scheme().map(|scheme| scheme.as_str()).unwrap_or_else(|| "");
-        let host = req.uri().host().unwrap_or("");
-        let naked_path = req.uri().to_string();
-        let path = req.uri().path_and_query().map(|v| v.to_string()).unwrap_or(naked_path);
-        
-        let uri = if let Some(port) = req.uri().port() {
-            format!("{}_{}://{}:{}{}", req.method(), scheme, host, port, path)
-        } else {
-            format!("{}_{}://{}{}", req.method(), scheme, host, path)
-        };
-        let vary: Vec<&str> = vary_content.split(',').map(|word| word.trim()).collect();
-
-        let mut vary_headers = BTreeMap::new();
-        vary.iter().for_each(|&header| {
-            let value = req.headers().get(header).map(|it| it.to_str().unwrap_or("")).unwrap_or_else(|| "");
-            vary_headers.insert(header.to_string(), value);
-        });
-        CacheKeyWithVary { uri, vary_headers }
+    #[test]
+    fn hashed_keys_keep_their_primary_key() {
+        let key_format = KeyFormat::new("prod", true, Vec::new(), false);
+        let primary_key = key_format.get_primary_key("GET", &format!("http://example.com/{}", "a".repeat(4096)), &[]);
+        let variant_key = key_format.get_variant_key(&primary_key, &[("accept-encoding", Some("gzip"))]);
+        assert_eq!(primary_key.len(), "prod:v2:".len() + 64);
+        assert!(variant_key.starts_with(&format!("{}#", primary_key)));
+        assert_eq!(variant_key.len(), primary_key.len() + 65);
     }
-}
\ No newline at end of file
+}
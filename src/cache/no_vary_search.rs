@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+
+// Query params the origin says don't change the response
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum IgnoredParams {
+    #[default]
+    None,
+    Listed(Vec<String>),
+    AllExcept(Vec<String>),
+}
+
+// No-Vary-Search response header: which differences in the query of a request still match a stored response
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NoVarySearch {
+    key_order: bool,
+    params: IgnoredParams,
+}
+
+impl NoVarySearch {
+    // Dictionary structured field, e.g. key-order, params=("utm_source" "fbclid") or params, except=("id").
+    // None if it is invalid or doesn't relax anything, the response then only matches its own query
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut key_order = false;
+        let mut params = None;
+        let mut except = None;
+        for member in split_members(value)? {
+            let (name, value) = match member.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (member.trim(), None),
+            };
+            match (name, value) {
+                ("key-order", value) => key_order = parse_boolean(value)?,
+                ("params", Some(value)) if value.starts_with('(') => params = Some(IgnoredParams::Listed(parse_inner_list(value)?)),
+                ("params", value) => params = parse_boolean(value)?.then(|| IgnoredParams::AllExcept(Vec::new())),
+                ("except", Some(value)) => except = Some(parse_inner_list(value)?),
+                // Unknown keys are ignored so the header can be extended
+                _ => {},
+            }
+        }
+        // except only applies when every param is ignored
+        let params = match (params, except) {
+            (Some(IgnoredParams::AllExcept(_)), Some(except)) => IgnoredParams::AllExcept(except),
+            (params, _) => params.unwrap_or_default(),
+        };
+        let no_vary_search = NoVarySearch { key_order, params };
+        Some(no_vary_search).filter(|no_vary_search| *no_vary_search != NoVarySearch::default())
+    }
+
+    // Two queries match if they have the same canonical form, names and values are compared decoded
+    pub fn canonicalize(&self, query: &str) -> String {
+        let mut params: Vec<(String, String)> = query.split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                (decode(name), decode(value))
+            })
+            .filter(|(name, _)| match &self.params {
+                IgnoredParams::None => true,
+                IgnoredParams::Listed(ignored) => !ignored.contains(name),
+                IgnoredParams::AllExcept(kept) => kept.contains(name),
+            })
+            .collect();
+        if self.key_order {
+            params.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        params.iter().map(|(name, value)| format!("{}={}", encode(name), encode(value))).collect::<Vec<String>>().join("&")
+    }
+}
+
+// Top level commas outside strings and inner lists
+fn split_members(value: &str) -> Option<Vec<&str>> {
+    let mut members = Vec::new();
+    let (mut start, mut depth, mut in_string, mut escaped) = (0, 0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                members.push(&value[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    if in_string || depth != 0 {
+        return None;
+    }
+    members.push(&value[start..]);
+    Some(members.into_iter().map(|member| member.trim()).filter(|member| !member.is_empty()).collect())
+}
+
+// A bare key is true, otherwise ?1 or ?0
+fn parse_boolean(value: Option<&str>) -> Option<bool> {
+    match value {
+        None | Some("?1") => Some(true),
+        Some("?0") => Some(false),
+        _ => None,
+    }
+}
+
+// ("a" "b"), only strings are allowed
+fn parse_inner_list(value: &str) -> Option<Vec<String>> {
+    let inner = value.strip_prefix('(')?.strip_suffix(')')?;
+    let mut items = Vec::new();
+    let mut chars = inner.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => continue,
+            '"' => {
+                let mut item = String::new();
+                loop {
+                    match chars.next()? {
+                        '\\' => item.push(chars.next()?),
+                        '"' => break,
+                        c => item.push(c),
+                    }
+                }
+                items.push(item);
+            },
+            _ => return None,
+        }
+    }
+    Some(items)
+}
+
+// Separators are escaped back so decoded names and values can't run into each other
+fn encode(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for c in component.chars() {
+        if matches!(c, '%' | '&' | '=' | '+') || c.is_whitespace() || c.is_control() {
+            let mut buffer = [0; 4];
+            for byte in c.encode_utf8(&mut buffer).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+// Params are compared as application/x-www-form-urlencoded decodes them
+fn decode(name: &str) -> String {
+    let bytes = name.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = name.get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            (b'+', None) => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            (byte, None) => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_header() {
+        assert_eq!(NoVarySearch::parse("key-order"), Some(NoVarySearch { key_order: true, params: IgnoredParams::None }));
+        assert_eq!(NoVarySearch::parse(r#"params=("utm_source" "fbclid")"#), Some(NoVarySearch { key_order: false, params: IgnoredParams::Listed(vec!["utm_source".to_string(), "fbclid".to_string()]) }));
+        assert_eq!(NoVarySearch::parse(r#"params, except=("id"), key-order"#), Some(NoVarySearch { key_order: true, params: IgnoredParams::AllExcept(vec!["id".to_string()]) }));
+        assert_eq!(NoVarySearch::parse("key-order=?0"), None);
+        assert_eq!(NoVarySearch::parse(r#"params=("unterminated)"#), None);
+    }
+
+    #[test]
+    fn matches_queries() {
+        let no_vary_search = NoVarySearch::parse(r#"key-order, params=("utm_source")"#).unwrap();
+        assert_eq!(no_vary_search.canonicalize("b=2&utm_source=x&a=1"), no_vary_search.canonicalize("a=1&b=2"));
+        assert_ne!(no_vary_search.canonicalize("a=1"), no_vary_search.canonicalize("a=2"));
+
+        let no_vary_search = NoVarySearch::parse(r#"params, except=("id")"#).unwrap();
+        assert_eq!(no_vary_search.canonicalize("id=1&page=2"), "id=1");
+        assert_eq!(no_vary_search.canonicalize("%69d=1"), "id=1");
+
+        let no_vary_search = NoVarySearch::parse("key-order").unwrap();
+        assert_eq!(no_vary_search.canonicalize("a=1+2&b"), no_vary_search.canonicalize("b=&a=1%202"));
+        assert_ne!(no_vary_search.canonicalize("a=1%262"), no_vary_search.canonicalize("a=1&2"));
+    }
+}
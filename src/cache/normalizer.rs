@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use crate::proxy::headers::ProxyHeaders;
+
+// Headers whose values are case-insensitive, lowercased even without a normalizer
+const CASE_INSENSITIVE: [&str; 4] = ["accept", "accept-charset", "accept-encoding", "accept-language"];
+
+const BOTS: [&str; 6] = ["bot", "crawler", "spider", "slurp", "bingpreview", "facebookexternalhit"];
+const TABLETS: [&str; 4] = ["ipad", "tablet", "kindle", "silk"];
+const MOBILES: [&str; 6] = ["mobi", "iphone", "ipod", "android", "windows phone", "opera mini"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Normalizer {
+    // desktop, mobile, tablet or bot
+    DeviceClass,
+    // Best entry of the list acceptable to the client, in the list order when several are equally acceptable
+    BestMatch(Vec<String>),
+}
+
+// Reduces the values of the headers listed in Vary to a few buckets so variants are shared between clients
+#[derive(Clone, Debug, Default)]
+pub struct VaryNormalizers(HashMap<String, Normalizer>);
+
+impl VaryNormalizers {
+    // header=device or header=value,value... separated by ';', e.g. "user-agent=device;accept-encoding=br,gzip"
+    pub fn parse(normalizers: &str) -> Self {
+        let mut parsed = HashMap::new();
+        for entry in normalizers.split(';').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            let (header, normalizer) = match entry.split_once('=') {
+                Some((header, normalizer)) => (header.trim().to_ascii_lowercase(), normalizer.trim()),
+                None => {
+                    tracing::warn!("Ignoring Vary normalizer {}, expected header=normalizer", entry);
+                    continue;
+                },
+            };
+            let normalizer = if normalizer.eq_ignore_ascii_case("device") {
+                Normalizer::DeviceClass
+            } else {
+                Normalizer::BestMatch(normalizer.split(',').map(|value| value.trim().to_ascii_lowercase()).filter(|value| !value.is_empty()).collect())
+            };
+            parsed.insert(header, normalizer);
+        }
+        VaryNormalizers(parsed)
+    }
+
+    // Value of the header the variant is selected on, None when the request doesn't send it
+    pub fn normalize(&self, header: &str, request_headers: &ProxyHeaders) -> Option<String> {
+        let value = canonicalize(header, &request_headers.get_combined(header)?);
+        match self.0.get(header) {
+            Some(Normalizer::DeviceClass) => Some(get_device_class(&value).to_string()),
+            // Nothing acceptable in the list, the value is kept as is rather than sharing a response it may not accept
+            Some(Normalizer::BestMatch(allowed)) => Some(get_best_match(header, &value, allowed).unwrap_or(value)),
+            None => Some(value),
+        }
+    }
+
+    // Request headers to send to the origin so it negotiates on the value the variant is stored under.
+    // Device classes aren't user agents, the origin still gets the original User-Agent
+    pub fn get_rewrites(&self, request_headers: &ProxyHeaders) -> Vec<(String, String)> {
+        self.0.iter()
+            .filter(|(_, normalizer)| matches!(normalizer, Normalizer::BestMatch(_)))
+            .filter_map(|(header, _)| Some((header.clone(), self.normalize(header, request_headers)?)))
+            .collect()
+    }
+}
+
+// Multiple fields are combined, whitespace runs collapsed and removed around list elements, they have identical semantics
+fn canonicalize(header: &str, value: &str) -> String {
+    let elements: Vec<String> = value.split(',')
+        .map(|element| element.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .collect();
+    let value = elements.join(",");
+    if CASE_INSENSITIVE.contains(&header) {
+        value.to_ascii_lowercase()
+    } else {
+        value
+    }
+}
+
+fn get_device_class(user_agent: &str) -> &'static str {
+    let user_agent = user_agent.to_ascii_lowercase();
+    if BOTS.iter().any(|bot| user_agent.contains(bot)) {
+        "bot"
+    // Android tablets don't say "mobile"
+    } else if TABLETS.iter().any(|tablet| user_agent.contains(tablet)) || (user_agent.contains("android") && !user_agent.contains("mobile")) {
+        "tablet"
+    } else if MOBILES.iter().any(|mobile| user_agent.contains(mobile)) {
+        "mobile"
+    } else {
+        "desktop"
+    }
+}
+
+// Accept-style lists of values with optional weights (RFC 9110 12.5)
+fn get_best_match(header: &str, value: &str, allowed: &[String]) -> Option<String> {
+    let ranges: Vec<(&str, f32)> = value.split(',').filter_map(|element| {
+        let mut params = element.split(';');
+        let range = params.next()?.trim();
+        let weight = params.filter_map(|param| param.trim().strip_prefix("q=")).next()
+            .and_then(|weight| weight.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        Some((range, weight)).filter(|(range, _)| !range.is_empty())
+    }).collect();
+
+    let mut best: Option<(&String, f32)> = None;
+    for candidate in allowed {
+        // The most specific range matching the candidate gives its weight
+        let weight = ranges.iter()
+            .filter_map(|(range, weight)| get_specificity(header, range, candidate).map(|specificity| (specificity, *weight)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, weight)| weight)
+            .unwrap_or(0.0);
+        if weight > 0.0 && best.map(|(_, best_weight)| weight > best_weight).unwrap_or(true) {
+            best = Some((candidate, weight));
+        }
+    }
+    best.map(|(candidate, _)| candidate.clone())
+}
+
+// How closely the range matches the value, None if it doesn't
+fn get_specificity(header: &str, range: &str, value: &str) -> Option<usize> {
+    if range == value {
+        return Some(3);
+    }
+    if range == "*" || range == "*/*" {
+        return Some(1);
+    }
+    match header {
+        "accept" => {
+            let range_type = range.strip_suffix("/*")?;
+            value.split('/').next().filter(|value_type| *value_type == range_type).map(|_| 2)
+        },
+        // en matches en-us and the other way around (RFC 4647 3.3.1, lenient)
+        "accept-language" => {
+            let prefixes = value.starts_with(&format!("{}-", range)) || range.starts_with(&format!("{}-", value));
+            Some(2).filter(|_| prefixes)
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_user_agents() {
+        assert_eq!(get_device_class("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0 Safari/537.36"), "desktop");
+        assert_eq!(get_device_class("Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) Mobile/15E148"), "mobile");
+        assert_eq!(get_device_class("Mozilla/5.0 (Linux; Android 13; SM-X700) AppleWebKit/537.36 Safari/537.36"), "tablet");
+        assert_eq!(get_device_class("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"), "bot");
+    }
+
+    #[test]
+    fn picks_best_allowed_value() {
+        let allowed = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<String>>();
+        assert_eq!(get_best_match("accept-encoding", "gzip,deflate,br", &allowed(&["br", "gzip"])), Some("br".to_string()));
+        assert_eq!(get_best_match("accept-encoding", "gzip,br;q=0.5", &allowed(&["br", "gzip"])), Some("gzip".to_string()));
+        assert_eq!(get_best_match("accept-encoding", "deflate", &allowed(&["br", "gzip"])), None);
+        assert_eq!(get_best_match("accept-language", "fr-ch,fr;q=0.9,en;q=0.8", &allowed(&["en", "fr"])), Some("fr".to_string()));
+        assert_eq!(get_best_match("accept", "text/*;q=0.5,application/json", &allowed(&["text/html", "application/json"])), Some("application/json".to_string()));
+        assert_eq!(get_best_match("accept", "image/webp,*/*;q=0.1", &allowed(&["text/html", "image/webp"])), Some("image/webp".to_string()));
+    }
+
+    #[test]
+    fn rewrites_negotiated_headers() {
+        let normalizers = VaryNormalizers::parse("user-agent=device;accept-encoding=br,gzip");
+        let mut header_map = http::HeaderMap::new();
+        header_map.insert("user-agent", "Mozilla/5.0 (iPhone) Mobile/15E148".parse().unwrap());
+        header_map.insert("accept-encoding", "gzip, deflate, br".parse().unwrap());
+        assert_eq!(normalizers.get_rewrites(&ProxyHeaders::from(&header_map)), vec![("accept-encoding".to_string(), "br".to_string())]);
+
+        // Nothing acceptable in the list, the value is left alone
+        header_map.insert("accept-encoding", "Deflate,  identity".parse().unwrap());
+        assert_eq!(normalizers.normalize("accept-encoding", &ProxyHeaders::from(&header_map)), Some("deflate,identity".to_string()));
+    }
+}
@@ -0,0 +1,77 @@
+use super::cache_control::CacheControlResponse;
+use crate::proxy::headers::ProxyHeaders;
+
+// Status codes that are cacheable by default (RFC 9110 15.1), minus 206 as we don't combine partial content
+const HEURISTICALLY_CACHEABLE_STATUS: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+// Store decision for a shared cache (RFC 9111 3 and 3.5)
+pub fn is_storable(status: u16, cache_control: &CacheControlResponse, headers: &ProxyHeaders, with_authorization: bool) -> bool {
+    if cache_control.is_no_store() {
+        tracing::debug!("Not storable: no-store");
+        return false;
+    }
+    if cache_control.is_private() {
+        tracing::debug!("Not storable: private");
+        return false;
+    }
+    if headers.get_combined("vary").map(|vary| vary.split(',').any(|header| header.trim() == "*")).unwrap_or(false) {
+        tracing::debug!("Not storable: Vary *");
+        return false;
+    }
+    // Personalised responses are only shared if the origin explicitly says so
+    if headers.contains_key("set-cookie") && !cache_control.is_public() {
+        tracing::debug!("Not storable: set-cookie without public");
+        return false;
+    }
+    if with_authorization && !(cache_control.is_public() || cache_control.has_must_revalidate() || cache_control.get_s_maxage().is_some()) {
+        tracing::debug!("Not storable: authorization without public, must-revalidate or s-maxage");
+        return false;
+    }
+    if !is_status_cacheable(status, cache_control, headers) {
+        tracing::debug!("Not storable: status {}", status);
+        return false;
+    }
+    true
+}
+
+// Other status codes need explicit freshness, and errors from the origin are never stored
+fn is_status_cacheable(status: u16, cache_control: &CacheControlResponse, headers: &ProxyHeaders) -> bool {
+    if HEURISTICALLY_CACHEABLE_STATUS.contains(&status) {
+        return true;
+    }
+    let has_explicit_freshness = cache_control.get_s_maxage().is_some() || cache_control.get_max_age().is_some() || headers.contains_key("expires");
+    // 304 only make sense for the conditional request that triggered them
+    status < 500 && status != 304 && has_explicit_freshness
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_private_and_personalised_responses() {
+        assert!(is_storable(200, &CacheControlResponse::from("max-age=60"), &ProxyHeaders::from_pairs(&[]), false));
+        assert!(!is_storable(200, &CacheControlResponse::from("no-store"), &ProxyHeaders::from_pairs(&[]), false));
+        assert!(!is_storable(200, &CacheControlResponse::from("private, max-age=60"), &ProxyHeaders::from_pairs(&[]), false));
+        assert!(!is_storable(200, &CacheControlResponse::from("max-age=60"), &ProxyHeaders::from_pairs(&[("vary", "accept, *")]), false));
+        assert!(!is_storable(200, &CacheControlResponse::from("max-age=60"), &ProxyHeaders::from_pairs(&[("set-cookie", "a=1")]), false));
+        assert!(is_storable(200, &CacheControlResponse::from("public, max-age=60"), &ProxyHeaders::from_pairs(&[("set-cookie", "a=1")]), false));
+    }
+
+    #[test]
+    fn requires_explicit_permission_with_authorization() {
+        assert!(!is_storable(200, &CacheControlResponse::from("max-age=60"), &ProxyHeaders::from_pairs(&[]), true));
+        assert!(is_storable(200, &CacheControlResponse::from("s-maxage=60"), &ProxyHeaders::from_pairs(&[]), true));
+        assert!(is_storable(200, &CacheControlResponse::from("must-revalidate"), &ProxyHeaders::from_pairs(&[]), true));
+        assert!(!is_storable(200, &CacheControlResponse::from("proxy-revalidate, max-age=60"), &ProxyHeaders::from_pairs(&[]), true));
+    }
+
+    #[test]
+    fn stores_other_statuses_only_with_explicit_freshness() {
+        assert!(is_storable(404, &CacheControlResponse::from(""), &ProxyHeaders::from_pairs(&[]), false));
+        assert!(!is_storable(302, &CacheControlResponse::from(""), &ProxyHeaders::from_pairs(&[]), false));
+        assert!(is_storable(302, &CacheControlResponse::from("max-age=60"), &ProxyHeaders::from_pairs(&[]), false));
+        assert!(!is_storable(503, &CacheControlResponse::from("max-age=60"), &ProxyHeaders::from_pairs(&[]), false));
+        assert!(!is_storable(304, &CacheControlResponse::from("max-age=60"), &ProxyHeaders::from_pairs(&[]), false));
+    }
+}
@@ -0,0 +1,32 @@
+// Value of the cacher_status header telling how the response was produced
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    // Fresh response from the cache
+    Hit,
+    // Stale response from the cache, refreshed in the background
+    Stale,
+    // Stored response was expired and had to be fetched again
+    Expired,
+    // Stored response was expired and the origin confirmed it with a 304
+    Revalidated,
+    // Nothing in the cache
+    Miss,
+    // Response not cacheable
+    Dynamic,
+    // Cache store unavailable, straight from the origin
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Expired => "EXPIRED",
+            CacheStatus::Revalidated => "REVALIDATED",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Dynamic => "DYNAMIC",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// Canonical form of a path and query so equivalent URLs share their cache entries (RFC 3986 6.2.2)
+pub fn normalize(path_and_query: &str) -> String {
+    let (path, query) = match path_and_query.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path_and_query, None),
+    };
+    let path = remove_dot_segments(&normalize_escapes(path));
+    let query = query.map(normalize_query).unwrap_or_default();
+    if query.is_empty() {
+        path
+    } else {
+        format!("{}?{}", path, query)
+    }
+}
+
+// Params with an empty value are dropped, a bare name is kept as a flag. The order of the values of a
+// repeated param is kept, some applications depend on it
+fn normalize_query(query: &str) -> String {
+    let mut params: Vec<String> = Vec::new();
+    for param in query.split('&').map(normalize_escapes) {
+        if param.is_empty() || param.ends_with('=') || params.contains(&param) {
+            continue;
+        }
+        params.push(param);
+    }
+    params.sort_by(|a, b| a.split('=').next().cmp(&b.split('=').next()));
+    params.join("&")
+}
+
+// Unreserved characters are decoded, other escapes get uppercase hex digits
+fn normalize_escapes(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut normalized = String::with_capacity(component.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit() {
+            let byte = u8::from_str_radix(&component[i + 1..i + 3], 16).unwrap_or_default();
+            if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+                normalized.push(byte as char);
+            } else {
+                normalized.push_str(&format!("%{:02X}", byte));
+            }
+            i += 3;
+        } else {
+            let c = component[i..].chars().next().unwrap_or_default();
+            normalized.push(c);
+            i += c.len_utf8();
+        }
+    }
+    normalized
+}
+
+// Empty segments are collapsed, "." and ".." resolved (RFC 3986 5.2.4)
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/').skip(1) {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {},
+            ".." => {
+                segments.pop();
+            },
+            segment => segments.push(segment),
+        }
+    }
+    if segments.is_empty() {
+        "/".to_string()
+    } else if trailing_slash {
+        format!("/{}/", segments.join("/"))
+    } else {
+        format!("/{}", segments.join("/"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize("/a//b/./c/../d"), "/a/b/d");
+        assert_eq!(normalize("/a/b/"), "/a/b/");
+        assert_eq!(normalize("/a/.."), "/");
+        assert_eq!(normalize("/%7euser/%2fdir%2F%41"), "/~user/%2Fdir%2FA");
+    }
+
+    #[test]
+    fn normalizes_queries() {
+        assert_eq!(normalize("/?b=2&a=1"), normalize("/?a=1&b=2"));
+        assert_eq!(normalize("/?b=2&&a=1&empty=&b=2&flag"), "/?a=1&b=2&flag");
+        assert_eq!(normalize("/?tag=z&tag=a"), "/?tag=z&tag=a");
+        assert_eq!(normalize("/?q=%2a%41"), "/?q=%2AA");
+        assert_eq!(normalize("/a?empty="), "/a");
+    }
+}
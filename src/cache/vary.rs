@@ -0,0 +1,103 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::headers::ProxyHeaders;
+use super::KeyFormat;
+use super::no_vary_search::NoVarySearch;
+use super::normalizer::VaryNormalizers;
+
+// One stored response of a URL, selected by the request headers listed in its Vary header (RFC 9111 4.1)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Variant {
+    pub key: String,
+    // Lowercase header names from the Vary header
+    headers: Vec<String>,
+    // Normalized values of these headers in the request that produced the response, None when absent
+    values: Vec<Option<String>>,
+    // Query params of the request as they go into the primary key
+    query: String,
+    // Requests with other queries may match too, the variant is then indexed under the URL without its query
+    no_vary_search: Option<NoVarySearch>,
+    stored_at: u64,
+}
+
+impl Variant {
+    pub fn new(primary_key: &str, vary_content: &str, request_headers: &ProxyHeaders, query: &str, no_vary_search: Option<NoVarySearch>, normalizers: &VaryNormalizers, key_format: &KeyFormat) -> Self {
+        let mut headers: Vec<String> = vary_content.split(',')
+            .map(|header| header.trim().to_ascii_lowercase())
+            .filter(|header| !header.is_empty())
+            .collect();
+        headers.sort();
+        headers.dedup();
+        let values: Vec<Option<String>> = headers.iter().map(|header| normalizers.normalize(header, request_headers)).collect();
+
+        // Requests with equivalent queries share the variant, '?' can't be a header name
+        let canonical_query = no_vary_search.as_ref().map(|no_vary_search| no_vary_search.canonicalize(query));
+        let mut secondary: Vec<(&str, Option<&str>)> = canonical_query.iter().map(|query| ("?", Some(query.as_str()))).collect();
+        secondary.extend(headers.iter().zip(values.iter()).map(|(header, value)| (header.as_str(), value.as_deref())));
+
+        // Responses without Vary are stored under the primary key
+        let key = if secondary.is_empty() {
+            primary_key.to_string()
+        } else {
+            key_format.get_variant_key(primary_key, &secondary)
+        };
+        let stored_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0);
+        Variant { key, headers, values, query: query.to_string(), no_vary_search, stored_at }
+    }
+
+    // Every header nominated by Vary must have the same value in the request as in the original one,
+    // and the query the same as the original one once No-Vary-Search is applied
+    pub fn matches(&self, request_headers: &ProxyHeaders, query: &str, normalizers: &VaryNormalizers) -> bool {
+        let query_matches = match &self.no_vary_search {
+            Some(no_vary_search) => no_vary_search.canonicalize(query) == no_vary_search.canonicalize(&self.query),
+            None => query == self.query,
+        };
+        query_matches && self.headers.iter().zip(self.values.iter())
+            .all(|(header, value)| &normalizers.normalize(header, request_headers) == value)
+    }
+}
+
+// Key of the stored response matching the request, the most recent one if several do (RFC 9111 4.1)
+pub fn select_variant(variants: &[String], request_headers: &ProxyHeaders, query: &str, normalizers: &VaryNormalizers) -> Option<String> {
+    variants.iter()
+        .filter_map(|variant| serde_json::from_str::<Variant>(variant).ok())
+        .filter(|variant| variant.matches(request_headers, query, normalizers))
+        .max_by_key(|variant| variant.stored_at)
+        .map(|variant| variant.key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_matching_variant() {
+        let normalizers = VaryNormalizers::default();
+        let key_format = KeyFormat::new("test", false, Vec::new(), false);
+        let gzip = Variant::new("test:v2:GET|http://example.com/", "Accept-Encoding", &ProxyHeaders::from_pairs(&[("accept-encoding", "gzip, br")]), "", None, &normalizers, &key_format);
+        let identity = Variant::new("test:v2:GET|http://example.com/", "accept-encoding", &ProxyHeaders::from_pairs(&[]), "", None, &normalizers, &key_format);
+        let variants = vec![serde_json::to_string(&gzip).unwrap(), serde_json::to_string(&identity).unwrap()];
+
+        assert_eq!(gzip.key, "test:v2:GET|http://example.com/#accept-encoding=gzip,br");
+        assert_eq!(select_variant(&variants, &ProxyHeaders::from_pairs(&[("accept-encoding", "GZIP,  br")]), "", &normalizers), Some(gzip.key.clone()));
+        assert_eq!(select_variant(&variants, &ProxyHeaders::from_pairs(&[]), "", &normalizers), Some(identity.key.clone()));
+        assert_eq!(select_variant(&variants, &ProxyHeaders::from_pairs(&[("accept-encoding", "deflate")]), "", &normalizers), None);
+    }
+
+    #[test]
+    fn selects_variant_by_no_vary_search() {
+        let normalizers = VaryNormalizers::default();
+        let key_format = KeyFormat::new("test", false, Vec::new(), false);
+        let no_vary_search = NoVarySearch::parse(r#"key-order, params=("ref")"#);
+        let variant = Variant::new("test:v2:GET|http://example.com/a", "", &ProxyHeaders::from_pairs(&[]), "b=2&a=1&ref=x", no_vary_search, &normalizers, &key_format);
+        let plain = Variant::new("test:v2:GET|http://example.com/a", "", &ProxyHeaders::from_pairs(&[]), "", None, &normalizers, &key_format);
+        let variants = vec![serde_json::to_string(&variant).unwrap(), serde_json::to_string(&plain).unwrap()];
+
+        assert_eq!(variant.key, "test:v2:GET|http://example.com/a#?=a%3D1%26b%3D2");
+        assert_eq!(select_variant(&variants, &ProxyHeaders::from_pairs(&[]), "a=1&b=2&ref=y", &normalizers), Some(variant.key.clone()));
+        assert_eq!(select_variant(&variants, &ProxyHeaders::from_pairs(&[]), "a=1", &normalizers), None);
+        assert_eq!(select_variant(&variants, &ProxyHeaders::from_pairs(&[]), "", &normalizers), Some(plain.key.clone()));
+    }
+}
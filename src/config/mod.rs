@@ -1,26 +1,115 @@
-use crate::{BACKEND_HOST, HANDLE_VARY, REDIS_URL};
+use crate::cache::{KeyFormat, key_rules::KeyRule, normalizer::VaryNormalizers};
+use crate::{BACKEND_HOST, BREAKER_THRESHOLD, REDIS_COMMAND_TIMEOUT_MS, REDIS_CONNECT_TIMEOUT_MS, DISK_QUOTA, DISK_STORE_PATH, L1_TTL, COALESCING_TIMEOUT_MS, DEFAULT_TTL, HANDLE_VARY, HASH_KEYS, KEY_RULES, HIT_FOR_PASS_TTL, LOCK_TTL_MS, NORMALIZE_URLS, MAX_OBJECT_SIZE, ORIGIN_TIMEOUT, MEMORY_STORE_SIZE, NAMESPACE, REDIS_URL, STALE_IF_ERROR, STALE_TTL, STORE, VARY_NORMALIZERS};
 
 
 
+#[derive(Clone, Debug)]
+pub enum StoreBackend {
+    Redis,
+    Memory,
+    // Memory in front of Redis
+    Tiered,
+    Disk,
+}
+
 #[derive(Clone)]
 pub struct CacherConfig {
     pub backend_host: String,
     pub handle_vary: bool,
+    pub vary_normalizers: VaryNormalizers,
+    pub key_format: KeyFormat,
     pub redis_url: String,
+    pub redis_connect_timeout_ms: u64,
+    pub redis_command_timeout_ms: u64,
+    pub breaker_threshold: u32,
+    pub store: StoreBackend,
+    pub memory_store_size: usize,
+    pub l1_ttl: u64,
+    pub disk_store_path: String,
+    pub disk_quota: u64,
+    pub disk_threshold: Option<usize>,
+    pub default_ttl: u64,
+    pub stale_ttl: u64,
+    pub stale_if_error: u64,
+    pub origin_timeout: u64,
+    pub coalescing_timeout_ms: u64,
+    pub lock_ttl_ms: u64,
+    pub hit_for_pass_ttl: u64,
+    pub max_object_size: usize,
+    pub purge_token: Option<String>,
 }
 
 impl CacherConfig {
     pub fn new() -> Self {
         let backend_host = std::env::var("CACHER_BACKEND").unwrap_or(BACKEND_HOST.to_string());
+        // Vary and No-Vary-Search are only honored when enabled, otherwise responses only vary on Accept-Language
+        // and each query has its own entry
         let env_handle_vary = std::env::var("CACHER_VARY").unwrap_or(HANDLE_VARY.to_string().to_ascii_lowercase());
         let handle_vary = match env_handle_vary.as_str() {
             "true" => true,
             "false" => false,
             _ => false,
         };
+        // Buckets for the values of headers listed in Vary, e.g. "user-agent=device;accept-language=en,fr"
+        let vary_normalizers = VaryNormalizers::parse(&std::env::var("CACHER_VARY_NORMALIZERS").unwrap_or(VARY_NORMALIZERS.to_string()));
+        // Environments sharing a store need different namespaces
+        let namespace = std::env::var("CACHER_NAMESPACE").unwrap_or(NAMESPACE.to_string());
+        // Fixed-length keys, PURGE then only removes the exact URL, not its forms with a query
+        let hash_keys = std::env::var("CACHER_HASH_KEYS").ok().and_then(|hash| hash.to_ascii_lowercase().parse::<bool>().ok()).unwrap_or(HASH_KEYS);
+        // JSON list of per-route rules choosing the query params, headers and cookies that go into the key
+        let key_rules = match serde_json::from_str::<Vec<KeyRule>>(&std::env::var("CACHER_KEY_RULES").unwrap_or(KEY_RULES.to_string())) {
+            Ok(key_rules) => key_rules,
+            Err(err) => {
+                tracing::warn!("Invalid key rules, using the full URL: {}", err);
+                Vec::new()
+            },
+        };
+        // Sort query params, canonicalize escapes and resolve dot segments before building keys
+        let normalize_urls = std::env::var("CACHER_NORMALIZE_URLS").ok().and_then(|normalize| normalize.to_ascii_lowercase().parse::<bool>().ok()).unwrap_or(NORMALIZE_URLS);
+        let key_format = KeyFormat::new(&namespace, hash_keys, key_rules, normalize_urls);
         let redis_url = std::env::var("CACHER_REDIS").unwrap_or(REDIS_URL.to_string());
+        let redis_connect_timeout_ms = std::env::var("CACHER_REDIS_CONNECT_TIMEOUT_MS").ok().and_then(|timeout| timeout.parse::<u64>().ok()).unwrap_or(REDIS_CONNECT_TIMEOUT_MS);
+        // A slow Redis must not hold requests longer than this, per command
+        let redis_command_timeout_ms = std::env::var("CACHER_REDIS_COMMAND_TIMEOUT_MS").ok().and_then(|timeout| timeout.parse::<u64>().ok()).unwrap_or(REDIS_COMMAND_TIMEOUT_MS);
+        // Consecutive store failures before requests bypass the cache
+        let breaker_threshold = std::env::var("CACHER_BREAKER_THRESHOLD").ok().and_then(|threshold| threshold.parse::<u32>().ok()).unwrap_or(BREAKER_THRESHOLD);
+        let store = match std::env::var("CACHER_STORE").unwrap_or(STORE.to_string()).to_ascii_lowercase().as_str() {
+            "redis" => StoreBackend::Redis,
+            "memory" => StoreBackend::Memory,
+            "tiered" => StoreBackend::Tiered,
+            "disk" => StoreBackend::Disk,
+            other => {
+                tracing::warn!("Unknown cache store {}, using redis", other);
+                StoreBackend::Redis
+            },
+        };
+        // Total size of keys and values kept by the memory store or the L1 tier, in bytes
+        let memory_store_size = std::env::var("CACHER_MEMORY_STORE_SIZE").ok().and_then(|size| size.parse::<usize>().ok()).unwrap_or(MEMORY_STORE_SIZE);
+        // Longest time an instance serves an entry from its L1 tier without checking Redis
+        let l1_ttl = std::env::var("CACHER_L1_TTL").ok().and_then(|ttl| ttl.parse::<u64>().ok()).unwrap_or(L1_TTL);
+        let disk_store_path = std::env::var("CACHER_DISK_STORE_PATH").unwrap_or(DISK_STORE_PATH.to_string());
+        // Total size of the files kept by the disk store, in bytes
+        let disk_quota = std::env::var("CACHER_DISK_QUOTA").ok().and_then(|quota| quota.parse::<u64>().ok()).unwrap_or(DISK_QUOTA);
+        // Entries larger than this go to the disk store instead of the main one, unset to keep everything in the main store
+        let disk_threshold = std::env::var("CACHER_DISK_THRESHOLD").ok().and_then(|threshold| threshold.parse::<usize>().ok());
+        let default_ttl = std::env::var("CACHER_DEFAULT_TTL").ok().and_then(|ttl| ttl.parse::<u64>().ok()).unwrap_or(DEFAULT_TTL);
+        // How long entries are kept in Redis once stale, for clients sending max-stale and for revalidation
+        let stale_ttl = std::env::var("CACHER_STALE_TTL").ok().and_then(|ttl| ttl.parse::<u64>().ok()).unwrap_or(STALE_TTL);
+        // Grace period to serve stale content on origin errors when the origin doesn't send stale-if-error
+        let stale_if_error = std::env::var("CACHER_STALE_IF_ERROR").ok().and_then(|ttl| ttl.parse::<u64>().ok()).unwrap_or(STALE_IF_ERROR);
+        let origin_timeout = std::env::var("CACHER_ORIGIN_TIMEOUT").ok().and_then(|timeout| timeout.parse::<u64>().ok()).unwrap_or(ORIGIN_TIMEOUT);
+        // How long concurrent misses wait for the request already fetching the same key
+        let coalescing_timeout_ms = std::env::var("CACHER_COALESCING_TIMEOUT_MS").ok().and_then(|timeout| timeout.parse::<u64>().ok()).unwrap_or(COALESCING_TIMEOUT_MS);
+        // Lifetime of the Redis lock taken by the instance fetching a key, in case it dies before releasing it
+        let lock_ttl_ms = std::env::var("CACHER_LOCK_TTL_MS").ok().and_then(|ttl| ttl.parse::<u64>().ok()).unwrap_or(LOCK_TTL_MS);
+        // How long requests for a key skip coalescing and the origin lock after a response that couldn't be stored
+        let hit_for_pass_ttl = std::env::var("CACHER_HIT_FOR_PASS_TTL").ok().and_then(|ttl| ttl.parse::<u64>().ok()).unwrap_or(HIT_FOR_PASS_TTL);
+        // Bodies larger than this are streamed to the client but not stored
+        let max_object_size = std::env::var("CACHER_MAX_OBJECT_SIZE").ok().and_then(|size| size.parse::<usize>().ok()).unwrap_or(MAX_OBJECT_SIZE);
+        // Bearer token PURGE requests must send, unset to disable PURGE
+        let purge_token = std::env::var("CACHER_PURGE_TOKEN").ok().filter(|token| !token.is_empty());
 
-        CacherConfig { backend_host, handle_vary, redis_url }
+        CacherConfig { backend_host, handle_vary, vary_normalizers, key_format, redis_url, redis_connect_timeout_ms, redis_command_timeout_ms, breaker_threshold, store, memory_store_size, l1_ttl, disk_store_path, disk_quota, disk_threshold, default_ttl, stale_ttl, stale_if_error, origin_timeout, coalescing_timeout_ms, lock_ttl_ms, hit_for_pass_ttl, max_object_size, purge_token }
     }
 
     pub fn get_backend(&self) -> &str {
@@ -30,4 +119,36 @@ impl CacherConfig {
     pub fn get_redis(&self) -> &str {
         self.redis_url.as_str()
     }
+
+    pub fn is_purge_enabled(&self) -> bool {
+        self.purge_token.is_some()
+    }
+
+    // Authorization: Bearer <token>, compared in constant time so the token can't be guessed byte by byte
+    pub fn is_purge_authorized(&self, authorization: Option<&str>) -> bool {
+        let (token, given) = match (&self.purge_token, authorization.and_then(|authorization| authorization.strip_prefix("Bearer "))) {
+            (Some(token), Some(given)) => (token.as_bytes(), given.trim().as_bytes()),
+            _ => return false,
+        };
+        token.len() == given.len() && token.iter().zip(given).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorizes_purge_with_token_only() {
+        let mut config = CacherConfig::new();
+        config.purge_token = None;
+        assert!(!config.is_purge_authorized(Some("Bearer secret")));
+
+        config.purge_token = Some("secret".to_string());
+        assert!(config.is_purge_authorized(Some("Bearer secret")));
+        assert!(!config.is_purge_authorized(Some("Bearer secreT")));
+        assert!(!config.is_purge_authorized(Some("Bearer secret2")));
+        assert!(!config.is_purge_authorized(Some("secret")));
+        assert!(!config.is_purge_authorized(None));
+    }
 }
\ No newline at end of file
@@ -5,6 +5,7 @@ use http::StatusCode;
 
 
 // Make our own error that wraps `anyhow::Error`.
+#[derive(Debug)]
 pub struct ProxyError(Error);
 
 
@@ -4,25 +4,27 @@ mod proxy_request;
 mod cache;
 mod proxy;
 mod config;
+mod store;
 
 use axum::{
-    http::{uri::Uri, Request, Response},
+    http::{header, uri::Uri, Method, Request, Response, StatusCode},
     routing::get,
     Router, extract::State
 };
 use hyper::{client::HttpConnector, Body};
-use r2d2::Pool;
-use redis::Commands;
 use std::{net::SocketAddr};
-use std::time::{Instant};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use anyhow::Result;
 
-use proxy_request::request::get_proxy_uri;
-use cache::{CacheKey, CacheKeyNoVary, CacheKeyWithVary, cache_control::CacheControlRequest};
+use proxy_request::request::{get_proxy_uri, keep_client_host, ProxyRequest};
+use cache::{CacheKey, CacheKeyNoVary, cache_control::{CacheControlRequest, CacheControlResponse}, conditional::ConditionalRequest, status::CacheStatus, coalescing::{Flight, RequestCoalescer, wait_for_leader}, hit_for_pass::HitForPass, lock::LockGuard, vary::select_variant};
+use proxy_response::response::ProxyResponse;
 use config::CacherConfig;
+use store::{CacheStore, new_store};
 
-use crate::{proxy::{response_from_origin_with_vary, response_from_origin_without_vary, response_from_cache, response_from_origin_without_cache}, cache::cache_control::CacheControlResponse};
+use crate::proxy::{response_from_origin_with_vary, response_from_origin_without_vary, response_from_cache, response_from_origin_without_cache, response_gateway_timeout, clone_request, StaleResponse};
 
 
 type Client = hyper::client::Client<HttpConnector, Body>;
@@ -30,16 +32,40 @@ type Client = hyper::client::Client<HttpConnector, Body>;
 #[derive(Clone)]
 pub struct ProxyState {
     http_client: hyper::client::Client<HttpConnector>,
-    redis_pool: Pool<redis::Client>,
+    store: Arc<dyn CacheStore>,
     config: CacherConfig,
+    coalescer: RequestCoalescer,
+    hit_for_pass: HitForPass,
 }
 
 const REDIS_URL: &str = "redis://127.0.0.1:6379/";
+const REDIS_CONNECT_TIMEOUT_MS: u64 = 1000;
+const REDIS_COMMAND_TIMEOUT_MS: u64 = 500;
+const BREAKER_THRESHOLD: u32 = 5;
+const BREAKER_PROBE_INTERVAL_MS: u64 = 1000;
+const STORE: &str = "redis";
+const MEMORY_STORE_SIZE: usize = 256 * 1024 * 1024;
+const SWEEP_INTERVAL: u64 = 30;
+const L1_TTL: u64 = 5;
+const DISK_STORE_PATH: &str = "/var/cache/cacher";
+const DISK_QUOTA: u64 = 10 * 1024 * 1024 * 1024;
 const BACKEND_HOST: &str = "http://stubr.rs:9191";
 const HANDLE_VARY: bool = false;
-const STATUS_HIT: &str = "HIT";
-const STATUS_MISS: &str = "MISS";
-const STATUS_DYNAMIC: &str = "DYNAMIC";
+const NAMESPACE: &str = "cacher";
+const HASH_KEYS: bool = false;
+const NORMALIZE_URLS: bool = false;
+// Tracking params don't change the response
+const KEY_RULES: &str = r#"[{"path": "*", "query": {"exclude": ["utm_*", "fbclid", "gclid"]}}]"#;
+const VARY_NORMALIZERS: &str = "user-agent=device;accept-encoding=br,gzip";
+const DEFAULT_TTL: u64 = 5;
+const STALE_TTL: u64 = 60;
+const STALE_IF_ERROR: u64 = 0;
+const ORIGIN_TIMEOUT: u64 = 30;
+const COALESCING_TIMEOUT_MS: u64 = 5000;
+const LOCK_TTL_MS: u64 = 10000;
+const LOCK_POLL_INTERVAL_MS: u64 = 50;
+const HIT_FOR_PASS_TTL: u64 = 10;
+const MAX_OBJECT_SIZE: usize = 10 * 1024 * 1024;
 
 #[tokio::main]
 async fn main() {
@@ -52,13 +78,16 @@ async fn main() {
         .init();
     
     let config = CacherConfig::new();
-    let redis_pool = get_redis_pool(&config).await.expect("Unable to create Redis connection pool");
+    let store = new_store(&config).await.expect("Unable to create cache store");
     let http_client = Client::new();
-    let state = ProxyState {http_client, redis_pool, config};
+    let coalescer = RequestCoalescer::default();
+    let hit_for_pass = HitForPass::default();
+    let state = ProxyState {http_client, store, config, coalescer, hit_for_pass};
 
-    let app = Router::with_state(state)
-                        .route("/", get(proxy))
-                        .route("/*path", get(proxy));
+    let app = Router::new()
+                        .route("/", get(proxy).fallback(purge))
+                        .route("/*path", get(proxy).fallback(purge))
+                        .with_state(state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
     tracing::info!("reverse proxy listening on {}", addr);
@@ -70,67 +99,339 @@ async fn main() {
 
 async fn proxy(State(state): State<ProxyState>, mut req: Request<Body>) -> Result<Response<Body>, error::ProxyError> {
     let start = Instant::now();
-    let mut redis_conn = state.redis_pool.get()?;
-
-    let duration = start.elapsed().as_micros();
-    tracing::debug!("Time elapsed init {}µs", duration);
 
     // Replace host(format scheme://host:port) in incoming request URI with the host we want to proxify to
+    keep_client_host(&mut req);
     let uri = get_proxy_uri(&req, state.config.get_backend()).await;
     *req.uri_mut() = Uri::try_from(uri)?;
     
 
     let cache_control = CacheControlRequest::try_from(&req).unwrap_or_default();
     tracing::info!("cache-control: {:?}", cache_control);
-    // No Vary if disabled or Vary == "" or Vary == "*"
-    // Enum cache status HIT - STALE - EXPIRED - MISS
-    // if in cache
-        // if < TTL -> HIT
-        // if > TTL && < stale_TTL -> STALE
-        // else -> EXPIRED
-    // if not in cache -> MISS
-    //if not cacheable -> DYNAMIC
-    let no_cache = false;
-    if no_cache {
-        let response = response_from_origin_without_cache(req, state.http_client).await?;
-        let duration = start.elapsed().as_micros();
-        tracing::info!("Time elapsed HIT {}µs", duration);
-        return Ok(response)
+    let conditional = ConditionalRequest::from(&req);
+
+    let primary_key = ProxyRequest::from(&req).get_primary_key(&state.config.key_format);
+    // The cache must never take the site down, go straight to the origin when the store fails
+    if !state.store.is_available() {
+        return response_bypass(req, &state, start).await;
+    }
+    let lookup = async {
+        // Without a matching variant, requests for the URL coalesce on the primary key
+        match get_cache_key(state.store.as_ref(), &req, &primary_key, &state.config).await? {
+            Some(cache_key) => {
+                let cached_content = state.store.get(&cache_key).await?;
+                Ok::<_, anyhow::Error>((cache_key, cached_content))
+            },
+            None => Ok((primary_key.clone(), None)),
+        }
+    }.await;
+    let (cache_key, cached_content) = match lookup {
+        Ok(lookup) => lookup,
+        Err(_) => return response_bypass(req, &state, start).await,
     };
+    let cached_response = cached_content.as_deref()
+        .and_then(|resp| serde_json::from_str::<ProxyResponse>(resp).ok());
+    let cache_status = get_cache_status(cached_response.as_ref(), &cache_control);
+
+    match cached_response {
+        Some(resp) if cache_status == CacheStatus::Hit => {
+            let proxy_response = response_from_cache(resp, &conditional, cache_status).await?;
+            let duration = start.elapsed().as_micros();
+            tracing::info!("Time elapsed HIT {}µs", duration);
+            Ok(proxy_response)
+        },
+        Some(resp) if cache_status == CacheStatus::Stale => {
+            // Only one background refresh per key at a time, in this instance and across instances
+            if let Flight::Leader(guard) = state.coalescer.join(&cache_key) {
+                if let Ok(Some(lock)) = state.store.lock(&cache_key, state.config.lock_ttl_ms).await {
+                    let lock = LockGuard::new(state.store.clone(), lock);
+                    let background_req = clone_request(&req)?;
+                    let cached_content = cached_content.clone().unwrap_or_default();
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let stale = serde_json::from_str::<ProxyResponse>(cached_content.as_str()).ok()
+                            .map(|response| StaleResponse { response, usable_on_error: true });
+                        // Lock and guard are released once the refreshed response is stored, or on failure when on_done is dropped
+                        let on_done = move || {
+                            drop(lock);
+                            drop(guard);
+                        };
+                        let refreshed = if state.config.handle_vary {
+                            response_from_origin_with_vary(background_req, &state, primary_key, stale, &ConditionalRequest::default(), on_done).await
+                        } else {
+                            response_from_origin_without_vary(background_req, &state, cache_key, stale, &ConditionalRequest::default(), on_done).await
+                        };
+                        if let Err(err) = refreshed {
+                            tracing::warn!("Background refresh failed: {:?}", err);
+                        }
+                    });
+                }
+            }
+            let proxy_response = response_from_cache(resp, &conditional, cache_status).await?;
+            let duration = start.elapsed().as_micros();
+            tracing::info!("Time elapsed STALE {}µs", duration);
+            Ok(proxy_response)
+        },
+        _ if cache_control.is_only_if_cached() => response_gateway_timeout().await,
+        _ if cache_control.is_no_store() => {
+            let proxy_response = response_from_origin_without_cache(req, state.http_client, CacheStatus::Dynamic).await?;
+            let duration = start.elapsed().as_micros();
+            tracing::info!("Time elapsed DYNAMIC {}µs", duration);
+            Ok(proxy_response)
+        },
+        stale => {
+            // The last response for the key couldn't be stored, waiting for another request would be in vain
+            let flight = if state.hit_for_pass.is_marked(&cache_key) { None } else { Some(state.coalescer.join(&cache_key)) };
+            let guard = match flight {
+                None => None,
+                Some(Flight::Leader(guard)) => Some(guard),
+                Some(Flight::Follower(receiver)) => {
+                    // Another request is fetching the same key, reuse its response once stored
+                    let timeout = Duration::from_millis(state.config.coalescing_timeout_ms);
+                    if wait_for_leader(receiver, timeout).await {
+                        if let Ok(Some(content)) = get_fresh_content(state.store.as_ref(), &req, &primary_key, &state.config, &cache_control).await {
+                            let resp = serde_json::from_str::<ProxyResponse>(content.as_str())?;
+                            let proxy_response = response_from_cache(resp, &conditional, CacheStatus::Hit).await?;
+                            let duration = start.elapsed().as_micros();
+                            tracing::info!("Time elapsed HIT after coalescing {}µs", duration);
+                            return Ok(proxy_response);
+                        }
+                    }
+                    // Uncacheable response or leader too slow, go to the origin ourselves
+                    None
+                },
+            };
+            let stale = stale.map(|response| {
+                let response_cc = CacheControlResponse::try_from(&response).unwrap_or_default();
+                let usable_on_error = response.freshness.is_stale_if_error(&cache_control, &response_cc, state.config.stale_if_error);
+                StaleResponse { response, usable_on_error }
+            });
+            let mut lock = None;
+            // Checked again, followers learn here that their leader's response couldn't be stored
+            let acquired = if state.hit_for_pass.is_marked(&cache_key) { None } else { Some(state.store.lock(&cache_key, state.config.lock_ttl_ms).await) };
+            let stale = match acquired {
+                None => stale,
+                Some(Ok(Some(acquired))) => {
+                    lock = Some(LockGuard::new(state.store.clone(), acquired));
+                    stale
+                },
+                // Another instance is fetching the same key, use our stale copy or wait for its response
+                Some(Ok(None)) => match stale {
+                    Some(stale) if stale.usable_on_error => {
+                        let proxy_response = response_from_cache(stale.response, &conditional, CacheStatus::Stale).await?;
+                        let duration = start.elapsed().as_micros();
+                        tracing::info!("Time elapsed STALE while locked {}µs", duration);
+                        return Ok(proxy_response);
+                    },
+                    stale => {
+                        if let Ok(Some(content)) = wait_for_replica(state.store.as_ref(), &req, &primary_key, &cache_key, &state.config, &cache_control).await {
+                            let resp = serde_json::from_str::<ProxyResponse>(content.as_str())?;
+                            let proxy_response = response_from_cache(resp, &conditional, CacheStatus::Hit).await?;
+                            let duration = start.elapsed().as_micros();
+                            tracing::info!("Time elapsed HIT after lock {}µs", duration);
+                            return Ok(proxy_response);
+                        }
+                        stale
+                    },
+                },
+                Some(Err(err)) => {
+                    tracing::warn!("Unable to take origin lock: {}", err);
+                    stale
+                },
+            };
+            // Lock and guard are released once the response is stored, followers then find it in the cache.
+            // If the origin request fails, on_done is dropped without being called and releases them too
+            let on_done = move || {
+                drop(lock);
+                drop(guard);
+            };
+            let proxy_response = if state.config.handle_vary {
+                response_from_origin_with_vary(req, &state, primary_key, stale, &conditional, on_done).await?
+            } else {
+                response_from_origin_without_vary(req, &state, cache_key, stale, &conditional, on_done).await?
+            };
+            let duration = start.elapsed().as_micros();
+            tracing::info!("Time elapsed MISS {}µs", duration);
+            Ok(proxy_response)
+        },
+    }
+}   
+
+// Store errors are logged by the store itself
+async fn response_bypass(req: Request<Body>, state: &ProxyState, start: Instant) -> Result<Response<Body>, error::ProxyError> {
+    let proxy_response = response_from_origin_without_cache(req, state.http_client.clone(), CacheStatus::Bypass).await?;
+    let duration = start.elapsed().as_micros();
+    tracing::info!("Time elapsed BYPASS {}µs", duration);
+    Ok(proxy_response)
+}
 
-    let vary_key = req.uri().path().to_ascii_lowercase();
-    let cache_key = if state.config.handle_vary {
-        let vary_content: Option<String> = redis_conn.get(&vary_key)?;
-        let vary_content = vary_content.unwrap_or_else(|| "".to_string());
-        CacheKeyWithVary::new_from_native(vary_content.as_str(), &req).get()
+// PURGE /path removes every stored variant of the URL, with any query when the request has none and keys aren't hashed.
+// Only enabled with CACHER_PURGE_TOKEN
+async fn purge(State(state): State<ProxyState>, mut req: Request<Body>) -> Result<Response<Body>, error::ProxyError> {
+    if req.method().as_str() != "PURGE" || !state.config.is_purge_enabled() {
+        return Ok(Response::builder().status(StatusCode::METHOD_NOT_ALLOWED).body(Body::empty())?);
+    }
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|authorization| authorization.to_str().ok());
+    if !state.config.is_purge_authorized(authorization) {
+        tracing::warn!("Rejected unauthorized PURGE of {}", req.uri());
+        return Ok(Response::builder().status(StatusCode::UNAUTHORIZED).header(header::WWW_AUTHENTICATE, "Bearer").body(Body::empty())?);
+    }
+    keep_client_host(&mut req);
+    let uri = get_proxy_uri(&req, state.config.get_backend()).await;
+    *req.uri_mut() = Uri::try_from(uri)?;
+    // Entries are stored under the key of the GET request, variant keys and indexes start with its primary key
+    *req.method_mut() = Method::GET;
+    let url_key = ProxyRequest::from(&req).get_url_key(&state.config.key_format);
+    let purged = state.store.purge(&url_key).await?;
+    tracing::info!("Purged {} entries of {}", purged, url_key);
+    Ok(Response::builder().status(StatusCode::OK).body(Body::from(format!("{}\n", purged)))?)
+}
+
+// Key of the stored response the request can be served from, None if no stored variant matches it.
+// Variants, and with them No-Vary-Search, need CACHER_VARY
+async fn get_cache_key(store: &dyn CacheStore, req: &Request<Body>, primary_key: &str, config: &CacherConfig) -> Result<Option<String>> {
+    if config.handle_vary {
+        let proxy_req = ProxyRequest::from(req);
+        let query = proxy_req.get_query(&config.key_format);
+        let variants = store.get_variants(primary_key).await?;
+        let cache_key = select_variant(&variants, proxy_req.get_headers(), &query, &config.vary_normalizers);
+        // Responses with No-Vary-Search are indexed under the URL without its query, a miss with a query looks there too
+        let search_key = proxy_req.get_search_key(&config.key_format);
+        if cache_key.is_some() || search_key == primary_key {
+            return Ok(cache_key);
+        }
+        let variants = store.get_variants(&search_key).await?;
+        Ok(select_variant(&variants, proxy_req.get_headers(), &query, &config.vary_normalizers))
     } else {
-        CacheKeyNoVary::from(&req).get()
+        Ok(Some(CacheKeyNoVary::new(req, &config.key_format).get()))
+    }
+}
+
+// Cached content for the request, only if it can be served as a HIT
+async fn get_fresh_content(store: &dyn CacheStore, req: &Request<Body>, primary_key: &str, config: &CacherConfig, cache_control: &CacheControlRequest) -> Result<Option<String>> {
+    let cache_key = match get_cache_key(store, req, primary_key, config).await? {
+        Some(cache_key) => cache_key,
+        None => return Ok(None),
     };
+    let cached_content = store.get(&cache_key).await?;
+    let is_hit = cached_content.as_deref()
+        .and_then(|resp| serde_json::from_str::<ProxyResponse>(resp).ok())
+        .map(|resp| get_cache_status(Some(&resp), cache_control) == CacheStatus::Hit)
+        .unwrap_or(false);
+    Ok(cached_content.filter(|_| is_hit))
+}
 
-    let cached_response: Option<String> = redis_conn.get(&cache_key)?;
+// Poll the cache while another instance holds the lock on the key
+async fn wait_for_replica(store: &dyn CacheStore, req: &Request<Body>, primary_key: &str, cache_key: &str, config: &CacherConfig, cache_control: &CacheControlRequest) -> Result<Option<String>> {
+    let deadline = Instant::now() + Duration::from_millis(config.coalescing_timeout_ms);
+    while Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(LOCK_POLL_INTERVAL_MS)).await;
+        if let Some(content) = get_fresh_content(store, req, primary_key, config, cache_control).await? {
+            return Ok(Some(content));
+        }
+        // Lock released or expired without a cacheable response
+        if !store.is_locked(cache_key).await? {
+            break;
+        }
+    }
+    Ok(None)
+}
 
-    if let Some(resp) = cached_response {
-        let proxy_response = response_from_cache(resp).await?;
-        let duration = start.elapsed().as_micros();
-        tracing::info!("Time elapsed HIT {}µs", duration);
-        Ok(proxy_response)
-    } else {
-        let proxy_response=  if state.config.handle_vary {
-            response_from_origin_with_vary(req, state.http_client, redis_conn, vary_key).await?
-        } else {
-            response_from_origin_without_vary(req, state.http_client, redis_conn, cache_key).await?
-        };
-        let duration = start.elapsed().as_micros();
-        tracing::info!("Time elapsed MISS {}µs", duration);
-        let cache_control = CacheControlResponse::try_from(&proxy_response).unwrap_or_default();
-        tracing::info!("cache-control: {:?}", cache_control);
-        Ok(proxy_response)
+// if in cache
+    // if fresh enough for the client -> HIT
+    // if within stale-while-revalidate -> STALE
+    // else -> EXPIRED
+// if not in cache -> MISS
+fn get_cache_status(cached_response: Option<&ProxyResponse>, cache_control: &CacheControlRequest) -> CacheStatus {
+    match cached_response {
+        Some(resp) => {
+            let response_cc = CacheControlResponse::try_from(resp).unwrap_or_default();
+            if resp.freshness.is_acceptable(cache_control, &response_cc) {
+                CacheStatus::Hit
+            } else if resp.freshness.is_stale_while_revalidate(cache_control, &response_cc) {
+                CacheStatus::Stale
+            } else {
+                CacheStatus::Expired
+            }
+        },
+        None => CacheStatus::Miss,
     }
-}   
+}
+
+#[cfg(test)]
+mod tests {
+    use cache::freshness::Freshness;
+    use store::memory_store::MemoryStore;
+    use proxy::testing::{FlakyStore, eventually, new_state, spawn_origin};
+    use store::breaker_store::BreakerStore;
 
+    use super::*;
 
-async fn get_redis_pool(config: &CacherConfig) -> Result<Pool<redis::Client>> {
-    let redis_client = redis::Client::open(config.get_redis())?;
-    let pool = r2d2::Pool::builder().max_size(500).build(redis_client)?;
-    Ok(pool)
+    fn stored_response(cache_control: &str, initial_age: u64) -> ProxyResponse<'static> {
+        let (parts, _) = Response::builder().header("cache-control", cache_control).body(()).unwrap().into_parts();
+        let mut response = ProxyResponse::from_parts(&parts).into_owned();
+        let stored_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        response.freshness = Freshness { stored_at, initial_age, lifetime: 100 };
+        response
+    }
+
+    #[test]
+    fn picks_cache_status() {
+        let request_cc = CacheControlRequest::default();
+        assert_eq!(get_cache_status(None, &request_cc), CacheStatus::Miss);
+        assert_eq!(get_cache_status(Some(&stored_response("max-age=100", 10)), &request_cc), CacheStatus::Hit);
+        assert_eq!(get_cache_status(Some(&stored_response("max-age=100, stale-while-revalidate=60", 130)), &request_cc), CacheStatus::Stale);
+        assert_eq!(get_cache_status(Some(&stored_response("max-age=100, stale-while-revalidate=60", 200)), &request_cc), CacheStatus::Expired);
+        assert_eq!(get_cache_status(Some(&stored_response("max-age=100, stale-while-revalidate=60, must-revalidate", 130)), &request_cc), CacheStatus::Expired);
+        // A client accepting the staleness gets a HIT before swr is considered
+        assert_eq!(get_cache_status(Some(&stored_response("max-age=100, stale-while-revalidate=60", 130)), &CacheControlRequest::from("max-stale=60")), CacheStatus::Hit);
+    }
+
+    #[tokio::test]
+    async fn only_if_cached_miss_is_gateway_timeout() {
+        // Nothing listens on port 1, any request reaching the origin fails
+        let state = new_state(Arc::new(MemoryStore::new(1024)), "http://127.0.0.1:1");
+        let req = Request::builder().uri("/a").header("cache-control", "only-if-cached").body(Body::empty()).unwrap();
+        let response = proxy(State(state), req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn bypasses_cache_when_store_fails() {
+        let origin = spawn_origin(|_| Response::new(Body::from("content"))).await;
+        let flaky = Arc::new(FlakyStore::default());
+        flaky.failing.store(true, std::sync::atomic::Ordering::Relaxed);
+        let store = Arc::new(BreakerStore::new(flaky, 2));
+        let state = new_state(store.clone(), &origin);
+
+        // The first failures are store errors, then the breaker is open and the store isn't asked anymore
+        for _ in 0..3 {
+            let req = Request::builder().uri("/a").body(Body::empty()).unwrap();
+            let response = proxy(State(state.clone()), req).await.unwrap();
+            assert_eq!(response.headers().get("cacher_status").unwrap(), "BYPASS");
+            assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "content");
+        }
+        assert!(!store.is_available());
+    }
+
+    #[tokio::test]
+    async fn uncacheable_response_skips_coalescing_and_lock() {
+        let origin = spawn_origin(|_| Response::builder().header("cache-control", "private").body(Body::from("content")).unwrap()).await;
+        let store: Arc<dyn CacheStore> = Arc::new(MemoryStore::new(1024 * 1024));
+        let state = new_state(store.clone(), &origin);
+        let request = || Request::builder().uri(format!("{}/a", origin)).body(Body::empty()).unwrap();
+        let cache_key = CacheKeyNoVary::new(&request(), &state.config.key_format).get();
+
+        let response = proxy(State(state.clone()), request()).await.unwrap();
+        assert_eq!(response.headers().get("cacher_status").unwrap(), "DYNAMIC");
+        assert!(state.hit_for_pass.is_marked(&cache_key));
+
+        // Another instance fetching the key doesn't hold the request back
+        assert!(eventually(|| async { !store.is_locked(&cache_key).await.unwrap() }).await);
+        let _lock = store.lock(&cache_key, 10_000).await.unwrap().unwrap();
+        let start = Instant::now();
+        let response = proxy(State(state.clone()), request()).await.unwrap();
+        assert_eq!(response.headers().get("cacher_status").unwrap(), "DYNAMIC");
+        assert!(start.elapsed() < Duration::from_millis(LOCK_POLL_INTERVAL_MS));
+    }
 }
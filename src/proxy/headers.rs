@@ -0,0 +1,134 @@
+use anyhow::Result;
+use http::{HeaderMap, HeaderValue, header::HeaderName};
+use serde::{Deserialize, Serialize};
+
+// Header fields in the order they were received, repeated fields included (Set-Cookie, Link, Via...)
+// Values are kept as ISO-8859-1 so that any byte, even non UTF-8, survives serialization
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct ProxyHeaders(Vec<(String, String)>);
+
+impl From<&HeaderMap> for ProxyHeaders {
+    fn from(headers: &HeaderMap) -> Self {
+        ProxyHeaders(headers.iter().map(|(k, v)| (k.to_string(), from_header_value(v))).collect())
+    }
+}
+
+impl TryFrom<&ProxyHeaders> for HeaderMap {
+    type Error = anyhow::Error;
+
+    fn try_from(proxy_headers: &ProxyHeaders) -> Result<Self, Self::Error> {
+        let mut headers = HeaderMap::new();
+        for (key, value) in proxy_headers.iter() {
+            headers.append(HeaderName::try_from(key)?, to_header_value(value)?);
+        }
+        Ok(headers)
+    }
+}
+
+impl ProxyHeaders {
+    // First value of the field, names are lowercase
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0.iter().filter(move |(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    // All the values of a list-based field (Cache-Control, Vary...) as if sent on one line (RFC 9110 5.3)
+    pub fn get_combined(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self.get_all(name).collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.0.iter().any(|(k, _)| k == name)
+    }
+
+    // Replace every value of the field, keeping the position of the first one
+    pub fn insert(&mut self, name: &str, value: String) {
+        match self.0.iter().position(|(k, _)| k == name) {
+            Some(position) => {
+                self.0[position].1 = value;
+                let mut index = 0;
+                self.0.retain(|(k, _)| {
+                    index += 1;
+                    k != name || index - 1 == position
+                });
+            },
+            None => self.0.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn append(&mut self, name: &str, value: String) {
+        self.0.push((name.to_string(), value));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(k, _)| k != name);
+    }
+
+    pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut keep: F) {
+        self.0.retain(|(k, _)| keep(k.as_str()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+// Test fixture, names are lowercased like the ones read from a request or a response
+#[cfg(test)]
+impl ProxyHeaders {
+    pub fn from_pairs(pairs: &[(&str, &str)]) -> Self {
+        ProxyHeaders(pairs.iter().map(|(name, value)| (name.to_ascii_lowercase(), value.to_string())).collect())
+    }
+}
+
+pub fn from_header_value(value: &HeaderValue) -> String {
+    value.as_bytes().iter().map(|&byte| byte as char).collect()
+}
+
+pub fn to_header_value(value: &str) -> Result<HeaderValue> {
+    let bytes: Vec<u8> = value.chars().map(|c| c as u8).collect();
+    Ok(HeaderValue::from_bytes(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_repeated_fields_and_bytes() {
+        let mut header_map = HeaderMap::new();
+        header_map.append("via", "1.1 a".parse().unwrap());
+        header_map.append("link", "</style.css>; rel=preload".parse().unwrap());
+        header_map.append("x-name", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+        header_map.append("via", "1.1 b".parse().unwrap());
+        header_map.append("link", "</app.js>; rel=preload".parse().unwrap());
+
+        let headers = ProxyHeaders::from(&header_map);
+        let json = serde_json::to_string(&headers).unwrap();
+        let deserialized: ProxyHeaders = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, headers);
+        let round_trip = HeaderMap::try_from(&deserialized).unwrap();
+
+        let fields = |map: &HeaderMap| map.iter().map(|(k, v)| (k.to_string(), v.as_bytes().to_vec())).collect::<Vec<(String, Vec<u8>)>>();
+        assert_eq!(fields(&round_trip), fields(&header_map));
+        assert_eq!(round_trip.get_all("link").iter().collect::<Vec<&HeaderValue>>(), vec!["</style.css>; rel=preload", "</app.js>; rel=preload"]);
+        assert_eq!(round_trip.get("x-name").unwrap().as_bytes(), b"caf\xe9");
+
+        // Fields interleaved in the stored entry keep their order
+        let mut headers = ProxyHeaders::default();
+        headers.append("via", "1.1 a".to_string());
+        headers.append("link", "</style.css>; rel=preload".to_string());
+        headers.append("via", "1.1 b".to_string());
+        let deserialized: ProxyHeaders = serde_json::from_str(&serde_json::to_string(&headers).unwrap()).unwrap();
+        assert_eq!(deserialized.iter().collect::<Vec<(&str, &str)>>(), vec![("via", "1.1 a"), ("link", "</style.css>; rel=preload"), ("via", "1.1 b")]);
+    }
+}
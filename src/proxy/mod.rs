@@ -1,16 +1,23 @@
-use http::{Response, Request};
-use http::header::HeaderName;
-use anyhow::Result;
-use hyper::{client::HttpConnector, Body};
-use r2d2::PooledConnection;
-use redis::{Commands, RedisError};
-
-use crate::proxy_response::response::{ProxyResponse, FromResponse};
+use http::{Response, Request, StatusCode};
+use http::header::{HeaderName, AUTHORIZATION, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use std::time::Duration;
+use anyhow::{Error, Result};
+use hyper::{client::HttpConnector, Body, body::{Bytes, HttpBody}};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::proxy_response::response::ProxyResponse;
 use crate::proxy_request::request::{ProxyRequest};
-use crate::cache::{CacheKey, CacheKeyWithVary};
-use crate::{error, STATUS_HIT, STATUS_MISS, STATUS_DYNAMIC};
+use crate::cache::{cache_control::CacheControlResponse, conditional::ConditionalRequest, freshness::Freshness, policy::is_storable, status::CacheStatus, no_vary_search::NoVarySearch, vary::Variant};
+use crate::config::CacherConfig;
+use crate::store::CacheStore;
+use headers::to_header_value;
+use crate::{error, ProxyState};
 
+pub(crate) mod headers;
 pub(crate) mod helpers;
+#[cfg(test)]
+pub(crate) mod testing;
 
 
 async fn add_header(mut response: Response<Body>, key: &str, value: Option<&str>) -> Result<Response<Body>> {
@@ -23,63 +30,432 @@ async fn add_header(mut response: Response<Body>, key: &str, value: Option<&str>
     }
 }
 
-pub async fn response_from_cache(resp: String) -> Result<Response<Body>, error::ProxyError> {
-    let response: ProxyResponse = serde_json::from_str(resp.as_str())?;
+pub async fn response_from_cache(mut response: ProxyResponse<'_>, conditional: &ConditionalRequest, cache_status: CacheStatus) -> Result<Response<Body>, error::ProxyError> {
+    response.headers.insert("age", response.freshness.get_current_age().to_string());
+    if conditional.is_not_modified(&response) {
+        response = response.into_not_modified();
+    }
     let mut proxy_response = Response::try_from(response)?;
-    proxy_response = add_header(proxy_response, "cacher_status", Some(STATUS_HIT)).await?;
+    proxy_response = add_header(proxy_response, "cacher_status", Some(cache_status.as_str())).await?;
     Ok(proxy_response)
 }
 
-pub async fn response_from_origin_with_vary(req: Request<Body>, 
-                http_client: hyper::client::Client<HttpConnector>, 
-                mut redis_conn: PooledConnection<redis::Client>,
-                vary_key: String) -> Result<Response<Body>, error::ProxyError> {
+// only-if-cached requests that can't be answered from the cache (RFC 9111 5.2.1.7)
+pub async fn response_gateway_timeout() -> Result<Response<Body>, error::ProxyError> {
+    let mut proxy_response = Response::builder().status(StatusCode::GATEWAY_TIMEOUT).body(Body::empty())?;
+    proxy_response = add_header(proxy_response, "cacher_status", Some(CacheStatus::Miss.as_str())).await?;
+    Ok(proxy_response)
+}
 
-    let proxy_req = ProxyRequest::from(&req);
+// Stored response that is no longer acceptable for the client
+pub struct StaleResponse<'a> {
+    pub response: ProxyResponse<'a>,
+    // Whether it is still within its stale-if-error window
+    pub usable_on_error: bool,
+}
 
-    let response = http_client.request(req).await?;
-    let proxy_resp = ProxyResponse::from_resp(response).await;
-    let vary_content = proxy_resp.headers.get("vary").unwrap_or(&String::default()).to_owned();
-    //If key with vary not cached yet (do we want to revalidate?)
-    let cache_key = CacheKeyWithVary::new_from_proxy(vary_content.as_str(), &proxy_req).get();
+enum OriginResponse<'a> {
+    // Status and headers, the body is still coming from the origin
+    Fetched(ProxyResponse<'a>, Body),
+    Revalidated(ProxyResponse<'a>),
+    StaleOnError(ProxyResponse<'a>),
+}
 
-    let response_to_cache = serde_json::to_string(&proxy_resp)?;
-    //Should be conditional. Do we cache? (vary == *, no cache-control, etc)
-    let _: Result<String, RedisError> = redis_conn.set(&cache_key, response_to_cache);
-    let _: Result<String, RedisError> = redis_conn.expire(&cache_key, 5); // TODO replace by real expiration value
-    let _: Result<String, RedisError> = redis_conn.set(&vary_key, vary_content);
+// Failed cache writes since startup
+static WRITE_FAILURES: AtomicU64 = AtomicU64::new(0);
 
-    let mut proxy_response = Response::try_from(proxy_resp)?;
-    proxy_response = add_header(proxy_response, "cacher_status", Some(STATUS_MISS)).await?;
+// Where and for how long the origin response goes in the cache
+struct CacheWrite {
+    store: Arc<dyn CacheStore>,
+    cache_key: String,
+    expiration: usize,
+    // Primary key and variant record added to its variant index along with the entry
+    variant: Option<(String, String)>,
+}
 
-    Ok(proxy_response)
+impl CacheWrite {
+    // The response is served whether it could be stored or not
+    async fn store(self, proxy_resp: &ProxyResponse<'_>) {
+        if let Err(err) = self.try_store(proxy_resp).await {
+            let failures = WRITE_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::warn!("Unable to store {} ({} failed writes so far): {}", self.cache_key, failures, err);
+        }
+    }
+
+    async fn try_store(&self, proxy_resp: &ProxyResponse<'_>) -> Result<()> {
+        let response_to_cache = serde_json::to_string(proxy_resp)?;
+        let variant = self.variant.as_ref().map(|(primary_key, record)| (primary_key.as_str(), record.as_str()));
+        self.store.put(&self.cache_key, &response_to_cache, self.expiration, variant).await
+    }
+}
+
+// Ask the origin for a full response, or a 304 if our stale copy is still valid (RFC 9111 4.3.1)
+async fn request_origin<'a>(mut req: Request<Body>,
+                http_client: &hyper::client::Client<HttpConnector>,
+                stale: Option<StaleResponse<'a>>,
+                config: &CacherConfig) -> Result<OriginResponse<'a>> {
+    // Client validators are checked against the full response instead, a 304 from the origin couldn't be stored.
+    // Requests that can't be stored go through response_from_origin_without_cache with their validators
+    req.headers_mut().remove(IF_NONE_MATCH);
+    req.headers_mut().remove(IF_MODIFIED_SINCE);
+    if let Some(stale) = stale.as_ref().map(|stale| &stale.response) {
+        if let Some(etag) = stale.headers.get("etag") {
+            req.headers_mut().insert(IF_NONE_MATCH, to_header_value(etag)?);
+        }
+        if let Some(last_modified) = stale.headers.get("last-modified") {
+            req.headers_mut().insert(IF_MODIFIED_SINCE, to_header_value(last_modified)?);
+        }
+    }
+
+    let response = match tokio::time::timeout(Duration::from_secs(config.origin_timeout), http_client.request(req)).await {
+        Ok(response) => response.map_err(Error::from),
+        Err(elapsed) => Err(Error::from(elapsed)),
+    };
+    let response = match response {
+        Ok(response) if !response.status().is_server_error() => response,
+        response => match stale {
+            Some(stale) if stale.usable_on_error => {
+                tracing::warn!("Origin failed, serving stale response");
+                return Ok(OriginResponse::StaleOnError(stale.response));
+            },
+            _ => response?,
+        },
+    };
+    match stale {
+        Some(mut stale) if response.status() == StatusCode::NOT_MODIFIED => {
+            tracing::debug!("Stored response revalidated");
+            stale.response.update_headers(response.headers());
+            Ok(OriginResponse::Revalidated(stale.response))
+        },
+        _ => {
+            let (parts, body) = response.into_parts();
+            Ok(OriginResponse::Fetched(ProxyResponse::from_parts(&parts), body))
+        },
+    }
+}
+
+// Compute freshness and tell whether the response should go to the cache, and for how long
+fn prepare_for_cache(proxy_resp: &mut ProxyResponse, config: &CacherConfig, with_authorization: bool) -> Option<usize> {
+    let cache_control = CacheControlResponse::try_from(&*proxy_resp).unwrap_or_default();
+    tracing::info!("cache-control: {:?}", cache_control);
+    proxy_resp.freshness = Freshness::new(&cache_control, &proxy_resp.headers, config.default_ttl);
+    let ttl = proxy_resp.freshness.get_remaining();
+
+    // Stale entries are kept around for max-stale, revalidation, stale-while-revalidate and stale-if-error
+    let stale_ttl = config.stale_ttl
+        .max(cache_control.get_stale_while_revalidate().unwrap_or(0))
+        .max(cache_control.get_stale_if_error().unwrap_or(config.stale_if_error));
+    // Already stale responses are only worth keeping if they can be revalidated
+    let worth_keeping = ttl > 0 || (proxy_resp.has_validators() && stale_ttl > 0);
+    if is_storable(proxy_resp.status, &cache_control, &proxy_resp.headers, with_authorization) && worth_keeping {
+        Some((ttl + stale_ttl) as usize)
+    } else {
+        None
+    }
 }
 
+// Requests for a key whose response can't be stored go to the origin without coalescing or locking for a while
+fn update_hit_for_pass(state: &ProxyState, key: &str, storable: bool) {
+    if storable {
+        state.hit_for_pass.unmark(key);
+    } else {
+        state.hit_for_pass.mark(key, Duration::from_secs(state.config.hit_for_pass_ttl));
+    }
+}
 
-pub async fn response_from_origin_without_vary(req: Request<Body>, 
-                http_client: hyper::client::Client<HttpConnector>, 
-                mut redis_conn: PooledConnection<redis::Client>, 
-                cache_key: String) -> Result<Response<Body>, error::ProxyError> {
-                    
-    let response = http_client.request(req).await?;
-    let proxy_resp = ProxyResponse::from_resp(response).await;
-    
-    let response_to_cache = serde_json::to_string(&proxy_resp)?;
-    //Should be conditional. Do we cache? (vary == *, no cache-control, etc)
-    let _: Result<String, RedisError> = redis_conn.set(&cache_key, response_to_cache);
-    let _: Result<String, RedisError> = redis_conn.expire(&cache_key, 5); // TODO replace by real expiration value
+// Send the response to the client, and to the cache once the whole body has been received
+async fn respond<F>(proxy_resp: ProxyResponse<'_>,
+                body: Option<Body>,
+                cacher_status: CacheStatus,
+                conditional: &ConditionalRequest,
+                cache_write: Option<CacheWrite>,
+                max_object_size: usize,
+                on_done: F) -> Result<Response<Body>>
+    where F: FnOnce() + Send + 'static {
 
-    let mut proxy_response = Response::try_from(proxy_resp)?;
-    proxy_response = add_header(proxy_response, "cacher_status", Some(STATUS_MISS)).await?;
+    // Client validators were not forwarded, answer them from the full response
+    let not_modified = conditional.is_not_modified(&proxy_resp);
+    let client_body = match (body, cache_write) {
+        // Revalidated, we already have the body
+        (None, cache_write) => {
+            if let Some(cache_write) = cache_write {
+                cache_write.store(&proxy_resp).await;
+            }
+            on_done();
+            None
+        },
+        (Some(body), None) => {
+            on_done();
+            Some(body).filter(|_| !not_modified)
+        },
+        (Some(body), Some(cache_write)) => {
+            let to_cache = proxy_resp.clone().into_owned();
+            if not_modified {
+                tokio::spawn(tee_body(body, None, to_cache, cache_write, max_object_size, on_done));
+                None
+            } else {
+                let (sender, client_body) = Body::channel();
+                tokio::spawn(tee_body(body, Some(sender), to_cache, cache_write, max_object_size, on_done));
+                Some(client_body)
+            }
+        },
+    };
 
+    let proxy_resp = if not_modified { proxy_resp.into_not_modified() } else { proxy_resp };
+    let mut proxy_response = match client_body {
+        Some(body) => proxy_resp.into_response(body)?,
+        None => Response::try_from(proxy_resp)?,
+    };
+    proxy_response = add_header(proxy_response, "cacher_status", Some(cacher_status.as_str())).await?;
     Ok(proxy_response)
 }
 
+// Forward the origin body to the client as it arrives while keeping a copy for the cache
+async fn tee_body<F>(mut body: Body,
+                mut sender: Option<hyper::body::Sender>,
+                mut proxy_resp: ProxyResponse<'static>,
+                cache_write: CacheWrite,
+                max_object_size: usize,
+                on_done: F)
+    where F: FnOnce() {
+
+    let mut buffer = Vec::new();
+    let mut storing = true;
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                tracing::warn!("Origin body failed: {}", err);
+                if let Some(sender) = sender.take() {
+                    sender.abort();
+                }
+                storing = false;
+                break;
+            },
+        };
+        if storing && buffer.len() + chunk.len() > max_object_size {
+            tracing::debug!("Response larger than {} bytes, not storing it", max_object_size);
+            storing = false;
+            buffer = Vec::new();
+        }
+        if storing {
+            buffer.extend_from_slice(&chunk);
+        }
+        // Keep downloading for the cache even if the client went away
+        if let Some(client) = sender.as_mut() {
+            if client.send_data(chunk).await.is_err() {
+                sender = None;
+            }
+        }
+        if !storing && sender.is_none() {
+            break;
+        }
+    }
+
+    if storing {
+        proxy_resp.set_body(Bytes::from(buffer));
+        cache_write.store(&proxy_resp).await;
+    }
+    on_done();
+}
+
+pub async fn response_from_origin_with_vary<F>(mut req: Request<Body>, 
+                state: &ProxyState,
+                primary_key: String,
+                stale: Option<StaleResponse<'_>>,
+                conditional: &ConditionalRequest,
+                on_done: F) -> Result<Response<Body>, error::ProxyError>
+    where F: FnOnce() + Send + 'static {
+
+    let proxy_req = ProxyRequest::from(&req);
+    let with_authorization = req.headers().contains_key(AUTHORIZATION);
+    for (header, value) in state.config.vary_normalizers.get_rewrites(proxy_req.get_headers()) {
+        if let (Ok(header), Ok(value)) = (HeaderName::try_from(header), to_header_value(&value)) {
+            req.headers_mut().insert(header, value);
+        }
+    }
+
+    let expired = stale.is_some();
+    let (mut proxy_resp, body, revalidated) = match request_origin(req, &state.http_client, stale, &state.config).await? {
+        OriginResponse::Fetched(proxy_resp, body) => (proxy_resp, Some(body), false),
+        OriginResponse::Revalidated(proxy_resp) => (proxy_resp, None, true),
+        OriginResponse::StaleOnError(proxy_resp) => {
+            on_done();
+            return response_from_cache(proxy_resp, conditional, CacheStatus::Stale).await;
+        },
+    };
+    let vary_content = proxy_resp.headers.get_combined("vary").unwrap_or_default();
+    let key_format = &state.config.key_format;
+    let no_vary_search = proxy_resp.headers.get_combined("no-vary-search").and_then(|no_vary_search| NoVarySearch::parse(&no_vary_search));
+    // Misses without a matching variant coalesce on the primary key of the request
+    let flight_key = primary_key.clone();
+    let primary_key = if no_vary_search.is_some() { proxy_req.get_search_key(key_format) } else { primary_key };
+    let variant = Variant::new(&primary_key, &vary_content, proxy_req.get_headers(), &proxy_req.get_query(key_format), no_vary_search, &state.config.vary_normalizers, key_format);
+    let cache_key = variant.key.clone();
+    let record = serde_json::to_string(&variant)?;
+
+    let expiration = prepare_for_cache(&mut proxy_resp, &state.config, with_authorization);
+    let cacher_status = get_cacher_status(expiration.is_some(), revalidated, expired);
+    update_hit_for_pass(state, &flight_key, expiration.is_some());
+    let cache_write = expiration.map(|expiration| CacheWrite {
+        store: state.store.clone(),
+        cache_key,
+        expiration,
+        variant: Some((primary_key, record)),
+    });
+
+    Ok(respond(proxy_resp, body, cacher_status, conditional, cache_write, state.config.max_object_size, on_done).await?)
+}
+
+
+pub async fn response_from_origin_without_vary<F>(req: Request<Body>, 
+                state: &ProxyState,
+                cache_key: String,
+                stale: Option<StaleResponse<'_>>,
+                conditional: &ConditionalRequest,
+                on_done: F) -> Result<Response<Body>, error::ProxyError>
+    where F: FnOnce() + Send + 'static {
+
+    let with_authorization = req.headers().contains_key(AUTHORIZATION);
+    let expired = stale.is_some();
+    let (mut proxy_resp, body, revalidated) = match request_origin(req, &state.http_client, stale, &state.config).await? {
+        OriginResponse::Fetched(proxy_resp, body) => (proxy_resp, Some(body), false),
+        OriginResponse::Revalidated(proxy_resp) => (proxy_resp, None, true),
+        OriginResponse::StaleOnError(proxy_resp) => {
+            on_done();
+            return response_from_cache(proxy_resp, conditional, CacheStatus::Stale).await;
+        },
+    };
+
+    let expiration = prepare_for_cache(&mut proxy_resp, &state.config, with_authorization);
+    let cacher_status = get_cacher_status(expiration.is_some(), revalidated, expired);
+    update_hit_for_pass(state, &cache_key, expiration.is_some());
+    let cache_write = expiration.map(|expiration| CacheWrite {
+        store: state.store.clone(),
+        cache_key,
+        expiration,
+        variant: None,
+    });
+
+    Ok(respond(proxy_resp, body, cacher_status, conditional, cache_write, state.config.max_object_size, on_done).await?)
+}
+
 pub async fn response_from_origin_without_cache(req: Request<Body>, 
-    http_client: hyper::client::Client<HttpConnector>) -> Result<Response<Body>, error::ProxyError> {
+    http_client: hyper::client::Client<HttpConnector>,
+    cacher_status: CacheStatus) -> Result<Response<Body>, error::ProxyError> {
         
     let mut proxy_response = http_client.request(req).await?;
-    proxy_response = add_header(proxy_response, "cacher_status", Some(STATUS_DYNAMIC)).await?;
+    proxy_response = add_header(proxy_response, "cacher_status", Some(cacher_status.as_str())).await?;
 
     Ok(proxy_response)
-}
\ No newline at end of file
+}
+
+fn get_cacher_status(stored: bool, revalidated: bool, expired: bool) -> CacheStatus {
+    match (stored, revalidated, expired) {
+        (_, true, _) => CacheStatus::Revalidated,
+        (false, false, _) => CacheStatus::Dynamic,
+        (true, false, true) => CacheStatus::Expired,
+        (true, false, false) => CacheStatus::Miss,
+    }
+}
+
+// Copy of a GET request that can outlive the client connection, for background refreshes
+pub fn clone_request(req: &Request<Body>) -> Result<Request<Body>> {
+    let mut builder = Request::builder().method(req.method()).uri(req.uri()).version(req.version());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = req.headers().clone();
+    }
+    Ok(builder.body(Body::empty())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::lock::LockGuard;
+    use crate::store::memory_store::MemoryStore;
+    use super::testing::{eventually, new_state, spawn_origin};
+    use super::*;
+
+    #[tokio::test]
+    async fn releases_lock_when_origin_fails() {
+        let store: Arc<dyn CacheStore> = Arc::new(MemoryStore::new(1024 * 1024));
+        let state = new_state(store.clone(), "http://127.0.0.1:1");
+        for handle_vary in [false, true] {
+            let lock = LockGuard::new(store.clone(), store.lock("key", 10_000).await.unwrap().unwrap());
+            // Nothing listens on port 1, the connection is refused
+            let req = Request::builder().uri("http://127.0.0.1:1/").body(Body::empty()).unwrap();
+            let on_done = move || drop(lock);
+            let response = if handle_vary {
+                response_from_origin_with_vary(req, &state, "key".to_string(), None, &ConditionalRequest::default(), on_done).await
+            } else {
+                response_from_origin_without_vary(req, &state, "key".to_string(), None, &ConditionalRequest::default(), on_done).await
+            };
+            assert!(response.is_err());
+
+            // The release happens in the background
+            assert!(eventually(|| async { !store.is_locked("key").await.unwrap() }).await);
+        }
+    }
+
+    fn origin_body(chunks: &[&'static str]) -> Body {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = chunks.iter().map(|chunk| Ok(Bytes::from(*chunk))).collect();
+        Body::wrap_stream(futures::stream::iter(chunks))
+    }
+
+    fn cache_write(store: &Arc<dyn CacheStore>) -> CacheWrite {
+        CacheWrite { store: store.clone(), cache_key: "key".to_string(), expiration: 60, variant: None }
+    }
+
+    fn to_cache() -> ProxyResponse<'static> {
+        let (parts, _) = Response::builder().status(StatusCode::OK).body(()).unwrap().into_parts();
+        ProxyResponse::from_parts(&parts).into_owned()
+    }
+
+    #[tokio::test]
+    async fn streams_large_body_without_storing_it() {
+        let store: Arc<dyn CacheStore> = Arc::new(MemoryStore::new(1024 * 1024));
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let on_done = {
+            let done = done.clone();
+            move || done.store(true, Ordering::Relaxed)
+        };
+        let (sender, client_body) = Body::channel();
+        let tee = tokio::spawn(tee_body(origin_body(&["0123456789", "abcdefghij", "KLMNO"]), Some(sender), to_cache(), cache_write(&store), 12, on_done));
+
+        let received = hyper::body::to_bytes(client_body).await.unwrap();
+        tee.await.unwrap();
+        assert_eq!(received, Bytes::from("0123456789abcdefghijKLMNO"));
+        assert_eq!(store.get("key").await.unwrap(), None);
+        assert!(done.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn keeps_storing_after_client_goes_away() {
+        let store: Arc<dyn CacheStore> = Arc::new(MemoryStore::new(1024 * 1024));
+        let (sender, client_body) = Body::channel();
+        drop(client_body);
+        tee_body(origin_body(&["0123456789", "abcdefghij"]), Some(sender), to_cache(), cache_write(&store), 1024, || {}).await;
+
+        let stored = serde_json::from_str::<ProxyResponse>(&store.get("key").await.unwrap().unwrap()).unwrap().into_owned();
+        let stored_body = hyper::body::to_bytes(Response::try_from(stored).unwrap().into_body()).await.unwrap();
+        assert_eq!(stored_body, Bytes::from("0123456789abcdefghij"));
+    }
+
+    #[tokio::test]
+    async fn answers_client_validators_and_stores_full_response() {
+        let origin = spawn_origin(|req: Request<Body>| {
+            // The origin must never see the client validators, it would answer 304 and nothing could be stored
+            let status = if req.headers().contains_key(IF_NONE_MATCH) { StatusCode::NOT_MODIFIED } else { StatusCode::OK };
+            Response::builder().status(status).header("etag", "\"v1\"").header("cache-control", "max-age=60").body(Body::from("content")).unwrap()
+        }).await;
+        let store: Arc<dyn CacheStore> = Arc::new(MemoryStore::new(1024 * 1024));
+        let state = new_state(store.clone(), &origin);
+        let req = Request::builder().uri(format!("{}/a", origin)).header(IF_NONE_MATCH, "\"v1\"").body(Body::empty()).unwrap();
+        let conditional = ConditionalRequest::from(&req);
+
+        let response = response_from_origin_without_vary(req, &state, "key".to_string(), None, &conditional, || {}).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(eventually(|| async { store.get("key").await.unwrap().is_some() }).await);
+        let stored = serde_json::from_str::<ProxyResponse>(&store.get("key").await.unwrap().unwrap()).unwrap().into_owned();
+        assert_eq!(stored.status, 200);
+    }
+}
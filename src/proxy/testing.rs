@@ -0,0 +1,106 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use hyper::{Body, Request, Response, Server, service::{make_service_fn, service_fn}};
+
+use crate::cache::{coalescing::RequestCoalescer, hit_for_pass::HitForPass, lock::CacheLock};
+use crate::config::CacherConfig;
+use crate::store::CacheStore;
+use crate::ProxyState;
+
+pub fn new_state(store: Arc<dyn CacheStore>, backend_host: &str) -> ProxyState {
+    let mut config = CacherConfig::new();
+    config.backend_host = backend_host.to_string();
+    ProxyState { http_client: hyper::Client::new(), store, config, coalescer: RequestCoalescer::default(), hit_for_pass: HitForPass::default() }
+}
+
+// Origin answering every request with handler on a free local port, returns its base URL
+pub async fn spawn_origin<F>(handler: F) -> String
+    where F: Fn(Request<Body>) -> Response<Body> + Clone + Send + Sync + 'static {
+
+    let make_service = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = handler(req);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let origin = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    origin
+}
+
+// Background work (stores, lock releases) finishes shortly after the response is returned
+pub async fn eventually<F, Fut>(check: F) -> bool
+    where F: Fn() -> Fut,
+          Fut: Future<Output = bool> {
+
+    for _ in 0..100 {
+        if check().await {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+// Store failing every operation while failing is set, calls counts the operations that reached it
+#[derive(Default)]
+pub struct FlakyStore {
+    pub failing: AtomicBool,
+    pub calls: AtomicU32,
+}
+
+impl FlakyStore {
+    fn check(&self) -> Result<()> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if self.failing.load(Ordering::Relaxed) {
+            anyhow::bail!("Store down");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CacheStore for FlakyStore {
+    async fn get(&self, _key: &str) -> Result<Option<String>> {
+        self.check().map(|_| None)
+    }
+
+    async fn put(&self, _key: &str, _value: &str, _ttl: usize, _variant: Option<(&str, &str)>) -> Result<()> {
+        self.check()
+    }
+
+    async fn delete(&self, _key: &str) -> Result<()> {
+        self.check()
+    }
+
+    async fn get_variants(&self, _primary_key: &str) -> Result<Vec<String>> {
+        self.check().map(|_| Vec::new())
+    }
+
+    async fn purge(&self, _url_key: &str) -> Result<usize> {
+        self.check().map(|_| 0)
+    }
+
+    async fn lock(&self, cache_key: &str, _ttl_ms: u64) -> Result<Option<CacheLock>> {
+        self.check().map(|_| Some(CacheLock::new(cache_key)))
+    }
+
+    async fn is_locked(&self, _cache_key: &str) -> Result<bool> {
+        self.check().map(|_| false)
+    }
+
+    async fn unlock(&self, _lock: CacheLock) -> Result<()> {
+        self.check()
+    }
+}
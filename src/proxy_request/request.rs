@@ -1,9 +1,10 @@
-use http::request::Request;
+use http::{header::HOST, request::Request, uri::Authority, HeaderValue};
 use hyper::Body;
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
 
+use crate::cache::KeyFormat;
 use crate::proxy::helpers::http_version_as_str;
+use crate::proxy::headers::ProxyHeaders;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct ProxyRequest<'a> {
@@ -13,7 +14,7 @@ pub struct ProxyRequest<'a> {
     pub host: String,
     pub port: Option<String>,
     pub uri: String,
-    pub headers: HashMap<String, String>,
+    pub headers: ProxyHeaders,
 }
 
 impl<'a> From<&Request<Body>> for ProxyRequest<'a> {
@@ -21,19 +22,75 @@ impl<'a> From<&Request<Body>> for ProxyRequest<'a> {
         let method = req.method().to_string();
         let version = http_version_as_str(req.version());
         let scheme = req.uri().scheme().unwrap().to_string();
-        let host = req.uri().host().unwrap_or_default().to_string();
+        // The URI points to the backend, the host the client asked for is in Host
+        let authority = req.headers().get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+            .or_else(|| req.uri().authority().cloned());
+        let host = authority.as_ref().map(|authority| authority.host().to_string()).unwrap_or_default();
+        let port = authority.as_ref().and_then(|authority| authority.port()).map(|port| port.to_string());
         let path = req.uri().to_string();
-        let port = req.uri().port().map(|port| port.to_string());
         let uri = req.uri().path_and_query().map(|v| v.to_string()).unwrap_or(path);
-        let headers: HashMap<String, String> = req.headers().iter().map(|(k, v)| (k.to_string(), String::from(v.to_str().unwrap_or("")))).collect();
+        let headers = ProxyHeaders::from(req.headers());
         ProxyRequest { method, version, scheme, host, port, uri, headers }
     }
 }
 
 impl<'a> ProxyRequest<'a> {
-    pub fn get_headers(&self) -> &HashMap<String,String> {
+    pub fn get_headers(&self) -> &ProxyHeaders {
         &self.headers
     }
+
+    // Method and full URL, what identifies the stored responses of a request before Vary (RFC 9111 4)
+    // What goes into it depends on the rule of the route
+    pub fn get_primary_key(&self, key_format: &KeyFormat) -> String {
+        self.get_key(key_format, true, true)
+    }
+
+    // Start of the primary keys of the URL, whatever the headers and cookies
+    pub fn get_url_key(&self, key_format: &KeyFormat) -> String {
+        self.get_key(key_format, true, false)
+    }
+
+    // Responses with No-Vary-Search are indexed under the URL without its query
+    pub fn get_search_key(&self, key_format: &KeyFormat) -> String {
+        self.get_key(key_format, false, true)
+    }
+
+    // Query params as they go into the primary key
+    pub fn get_query(&self, key_format: &KeyFormat) -> String {
+        let path_and_query = key_format.normalize(&self.uri);
+        let query = path_and_query.split_once('?').map(|(_, query)| query).unwrap_or_default();
+        key_format.get_rule(get_path(&path_and_query)).get_query(query)
+    }
+
+    fn get_key(&self, key_format: &KeyFormat, with_query: bool, with_fields: bool) -> String {
+        let path_and_query = key_format.normalize(&self.uri);
+        let path = get_path(&path_and_query);
+        let rule = key_format.get_rule(path);
+        let authority = match self.port.as_ref() {
+            Some(port) => format!("{}:{}", self.host.to_ascii_lowercase(), port),
+            None => self.host.to_ascii_lowercase(),
+        };
+        let url = rule.get_url(&self.scheme, &authority, if with_query { &path_and_query } else { path });
+        let fields = if with_fields { rule.get_fields(&self.headers) } else { Vec::new() };
+        let fields: Vec<(&str, Option<&str>)> = fields.iter().map(|(name, value)| (name.as_str(), value.as_deref())).collect();
+        key_format.get_primary_key(&self.method, &url, &fields)
+    }
+}
+
+fn get_path(path_and_query: &str) -> &str {
+    path_and_query.split('?').next().unwrap_or_default()
+}
+
+// HTTP/2 clients only send their host in the URI, keep it as Host before the URI is replaced by the backend one
+pub fn keep_client_host(req: &mut Request<Body>) {
+    if req.headers().contains_key(HOST) {
+        return;
+    }
+    if let Some(host) = req.uri().authority().and_then(|authority| HeaderValue::from_str(authority.as_str()).ok()) {
+        req.headers_mut().insert(HOST, host);
+    }
 }
 
 pub async fn get_proxy_uri(req: &Request<Body>, backend_host: &str) -> String {
@@ -44,4 +101,39 @@ pub async fn get_proxy_uri(req: &Request<Body>, backend_host: &str) -> String {
         .map(|v| v.as_str())
         .unwrap_or(path);
     format!("{}{}", backend_host, path_query)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::key_rules::KeyRule;
+    use super::*;
+
+    fn request(uri: &str, host: &str) -> Request<Body> {
+        let mut req = Request::builder().uri(uri).header("host", host).body(Body::empty()).unwrap();
+        keep_client_host(&mut req);
+        *req.uri_mut() = format!("http://backend:9191{}", req.uri().path_and_query().unwrap()).parse().unwrap();
+        req
+    }
+
+    #[test]
+    fn keys_on_client_host() {
+        let key_format = KeyFormat::new("test", false, Vec::new(), false);
+        let a = ProxyRequest::from(&request("/p?q=1", "A.example.com")).get_primary_key(&key_format);
+        let b = ProxyRequest::from(&request("/p?q=1", "b.example.com:8080")).get_primary_key(&key_format);
+        assert_eq!(a, "test:v2:GET|http://a.example.com/p?q%3D1");
+        assert_eq!(b, "test:v2:GET|http://b.example.com:8080/p?q%3D1");
+
+        let rules: Vec<KeyRule> = serde_json::from_str(r#"[{"path": "*", "host": false}]"#).unwrap();
+        let key_format = KeyFormat::new("test", false, rules, false);
+        let a = ProxyRequest::from(&request("/p?q=1", "a.example.com")).get_primary_key(&key_format);
+        let b = ProxyRequest::from(&request("/p?q=1", "b.example.com")).get_primary_key(&key_format);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn keeps_http2_authority_as_host() {
+        let mut req = Request::builder().uri("https://c.example.com/p").body(Body::empty()).unwrap();
+        keep_client_host(&mut req);
+        assert_eq!(req.headers().get(HOST).unwrap(), "c.example.com");
+    }
+}
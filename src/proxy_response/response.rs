@@ -1,57 +1,88 @@
-use std::collections::HashMap;
-use async_trait::async_trait;
 use axum::response::Response;
-use http::{response::{Parts, Builder}, StatusCode, HeaderValue, header::HeaderName, HeaderMap};
-use hyper::Body;
+use http::{response::{Parts, Builder}, StatusCode, HeaderMap};
+use hyper::{Body, body::Bytes};
 use anyhow::{Result, Error};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use crate::proxy::helpers::{get_http_version, http_version_as_str};
+use crate::proxy::headers::{ProxyHeaders, from_header_value};
+use crate::cache::freshness::Freshness;
 
 
-#[async_trait]
-pub trait FromResponse<T>: Sized {
-    async fn from_resp(resp: T) -> Self;
-}
-
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct ProxyResponse<'a> {
-    status: u16,
+    pub status: u16,
     version: &'a str,
-    pub headers: HashMap<String, String>,
-    body: String,
-}
-
-#[async_trait]
-impl<'a> FromResponse<Response<Body>> for ProxyResponse<'a> {
-    async fn from_resp(resp: Response<Body>) -> Self {
-        let status = resp.status().as_u16();
-        let (parts, body): (Parts, Body) = resp.into_parts();
-        let version = http_version_as_str(parts.version);
-        let headers: HashMap<String, String> = parts.headers.iter().map(|(k, v)| (k.to_string(), String::from(v.to_str().unwrap_or("")))).collect();
-        let body = get_response_body_as_string(body).await.unwrap_or_else(|_| "".to_string());
-        ProxyResponse { status, version, headers, body }
-    }
+    pub headers: ProxyHeaders,
+    #[serde(with = "base64_body")]
+    body: Bytes,
+    #[serde(default)]
+    pub freshness: Freshness,
 }
 
 impl<'a> TryFrom<ProxyResponse<'a>> for Response<Body> {
     type Error = Error;
 
     fn try_from (proxy_resp: ProxyResponse) -> Result<Self, Self::Error> {
-        let status = StatusCode::from_u16(proxy_resp.status)?;
-        let version = get_http_version(proxy_resp.version);
-        let body = Body::from(proxy_resp.body);
+        let body = Body::from(proxy_resp.body.clone());
+        proxy_resp.into_response(body)
+    }
+}
+
+// Header fields a 304 must not overwrite in the stored response (RFC 9111 3.2)
+const NOT_UPDATED_HEADERS: [&str; 5] = ["content-length", "content-encoding", "content-range", "transfer-encoding", "connection"];
+
+// Header fields a 304 Not Modified sent to the client carries over from the stored response (RFC 9110 15.4.5)
+const NOT_MODIFIED_HEADERS: [&str; 8] = ["cache-control", "content-location", "date", "etag", "expires", "vary", "last-modified", "age"];
+
+impl<'a> ProxyResponse<'a> {
+    // Status and headers only, the body is set once fully received
+    pub fn from_parts(parts: &Parts) -> Self {
+        let status = parts.status.as_u16();
+        let version = http_version_as_str(parts.version);
+        let headers = ProxyHeaders::from(&parts.headers);
+        ProxyResponse { status, version, headers, body: Bytes::new(), freshness: Freshness::default() }
+    }
+
+    pub fn set_body(&mut self, body: Bytes) {
+        self.body = body;
+    }
+
+    // Detach the response from the content it was deserialized from
+    pub fn into_owned(self) -> ProxyResponse<'static> {
+        let version = http_version_as_str(get_http_version(self.version));
+        ProxyResponse { status: self.status, version, headers: self.headers, body: self.body, freshness: self.freshness }
+    }
+
+    pub fn into_response(self, body: Body) -> Result<Response<Body>> {
+        let status = StatusCode::from_u16(self.status)?;
+        let version = get_http_version(self.version);
         let mut builder = Builder::new().status(status).version(version);
-        {
-            let mut default_headers = HeaderMap::new();
-            let headers = builder.headers_mut().unwrap_or(&mut default_headers);
-            for (key, value) in proxy_resp.headers.into_iter() {
-                headers.insert(HeaderName::try_from(&key)?, HeaderValue::from_bytes(value.as_bytes())?);
-            }
+        if let Some(headers) = builder.headers_mut() {
+            *headers = HeaderMap::try_from(&self.headers)?;
         }
         let response = builder.body(body)?;
         Ok(response)
     }
+
+    pub fn has_validators(&self) -> bool {
+        self.headers.contains_key("etag") || self.headers.contains_key("last-modified")
+    }
+
+    pub fn into_not_modified(mut self) -> Self {
+        self.headers.retain(|k| NOT_MODIFIED_HEADERS.contains(&k));
+        ProxyResponse { status: 304, version: self.version, headers: self.headers, body: Bytes::new(), freshness: self.freshness }
+    }
+
+    // Freshen the stored response with the headers of a 304 Not Modified (RFC 9111 4.3.4)
+    pub fn update_headers(&mut self, headers: &HeaderMap) {
+        headers.keys()
+            .filter(|k| !NOT_UPDATED_HEADERS.contains(&k.as_str()))
+            .for_each(|k| {
+                self.headers.remove(k.as_str());
+                headers.get_all(k).iter().for_each(|v| self.headers.append(k.as_str(), from_header_value(v)));
+            });
+    }
 }
 
 impl<'a> fmt::Display for ProxyResponse<'a> {
@@ -60,10 +91,50 @@ impl<'a> fmt::Display for ProxyResponse<'a> {
     }
 }
 
+// Bodies are raw bytes (images, gzip, protobuf...), stored as base64 in the JSON representation
+mod base64_body {
+    use hyper::body::Bytes;
+    use serde::{Deserialize, Deserializer, Serializer};
 
+    pub fn serialize<S: Serializer>(body: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(body))
+    }
 
-pub async fn get_response_body_as_string<'a>(body: Body) -> Result<String> {
-    let body_bytes = hyper::body::to_bytes(body).await?.to_vec();
-    let body = String::from_utf8(body_bytes)?;
-    Ok(body)
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(encoded).map(Bytes::from).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, enough to cover every byte value including invalid UTF-8 sequences
+    fn random_bytes(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed >> 24) as u8
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn binary_body_round_trip() {
+        let content = random_bytes(64 * 1024, 0x2545_f491_4f6c_dd1d);
+        assert!(String::from_utf8(content.clone()).is_err());
+        let origin_response = Builder::new().status(200).header("content-type", "image/png").body(Body::from(content.clone())).unwrap();
+
+        let (parts, body) = origin_response.into_parts();
+        let mut proxy_resp = ProxyResponse::from_parts(&parts);
+        proxy_resp.set_body(hyper::body::to_bytes(body).await.unwrap());
+        let stored = serde_json::to_string(&proxy_resp).unwrap();
+        let cached: ProxyResponse = serde_json::from_str(stored.as_str()).unwrap();
+        let response = Response::try_from(cached).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.to_vec(), content);
+    }
 }
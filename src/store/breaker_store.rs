@@ -0,0 +1,208 @@
+use std::future::Future;
+use std::sync::{Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::cache::lock::CacheLock;
+use super::CacheStore;
+
+// Key read by the probe, it doesn't need to exist
+const PROBE_KEY: &str = "cacher:probe";
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+// Opens after too many consecutive failures so requests stop waiting on a store that is down
+pub struct CircuitBreaker {
+    threshold: u32,
+    failures: AtomicU32,
+    open: AtomicBool,
+    // Errors are logged at most once per LOG_INTERVAL
+    last_log: Mutex<Option<Instant>>,
+    suppressed: AtomicU64,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32) -> Self {
+        CircuitBreaker { threshold, failures: AtomicU32::new(0), open: AtomicBool::new(false), last_log: Mutex::new(None), suppressed: AtomicU64::new(0) }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    fn record_failure(&self, err: &anyhow::Error) {
+        self.log_failure(err);
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.threshold && !self.open.swap(true, Ordering::Relaxed) {
+            tracing::error!("Cache store unavailable after {} failures, bypassing the cache", failures);
+        }
+    }
+
+    fn close(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        if self.open.swap(false, Ordering::Relaxed) {
+            tracing::info!("Cache store available again");
+        }
+    }
+
+    fn log_failure(&self, err: &anyhow::Error) {
+        let now = Instant::now();
+        let mut last_log = self.last_log.lock().unwrap();
+        if last_log.map(|last_log| now.duration_since(last_log) >= LOG_INTERVAL).unwrap_or(true) {
+            let suppressed = self.suppressed.swap(0, Ordering::Relaxed);
+            tracing::warn!("Cache store error: {} ({} more since last report)", err, suppressed);
+            *last_log = Some(now);
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Fails fast while the breaker is open, a background probe closes it once the store answers again
+pub struct BreakerStore {
+    store: Arc<dyn CacheStore>,
+    breaker: CircuitBreaker,
+}
+
+impl BreakerStore {
+    pub fn new(store: Arc<dyn CacheStore>, threshold: u32) -> Self {
+        BreakerStore { store, breaker: CircuitBreaker::new(threshold) }
+    }
+
+    async fn call<T, F>(&self, operation: F) -> Result<T>
+        where F: Future<Output = Result<T>> {
+
+        if self.breaker.is_open() {
+            anyhow::bail!("Cache store unavailable");
+        }
+        let result = operation.await;
+        match &result {
+            Ok(_) => self.breaker.record_success(),
+            Err(err) => self.breaker.record_failure(err),
+        }
+        result
+    }
+
+    pub fn spawn_probe(store: Weak<BreakerStore>, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let store = match store.upgrade() {
+                    Some(store) => store,
+                    None => break,
+                };
+                if store.breaker.is_open() {
+                    match store.store.get(PROBE_KEY).await {
+                        Ok(_) => store.breaker.close(),
+                        Err(err) => store.breaker.log_failure(&err),
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl CacheStore for BreakerStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        self.call(self.store.get(key)).await
+    }
+
+    async fn put(&self, key: &str, value: &str, ttl: usize, variant: Option<(&str, &str)>) -> Result<()> {
+        self.call(self.store.put(key, value, ttl, variant)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.call(self.store.delete(key)).await
+    }
+
+    async fn get_variants(&self, primary_key: &str) -> Result<Vec<String>> {
+        self.call(self.store.get_variants(primary_key)).await
+    }
+
+    // A purge can fail on its own size, that says nothing about the store serving requests
+    async fn purge(&self, url_key: &str) -> Result<usize> {
+        if self.breaker.is_open() {
+            anyhow::bail!("Cache store unavailable");
+        }
+        self.store.purge(url_key).await
+    }
+
+    async fn lock(&self, cache_key: &str, ttl_ms: u64) -> Result<Option<CacheLock>> {
+        self.call(self.store.lock(cache_key, ttl_ms)).await
+    }
+
+    async fn is_locked(&self, cache_key: &str) -> Result<bool> {
+        self.call(self.store.is_locked(cache_key)).await
+    }
+
+    async fn unlock(&self, lock: CacheLock) -> Result<()> {
+        self.call(self.store.unlock(lock)).await
+    }
+
+    fn is_available(&self) -> bool {
+        !self.breaker.is_open()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::testing::{FlakyStore, eventually};
+    use super::*;
+
+    fn new_store(threshold: u32) -> (Arc<FlakyStore>, Arc<BreakerStore>) {
+        let flaky = Arc::new(FlakyStore::default());
+        flaky.failing.store(true, Ordering::Relaxed);
+        (flaky.clone(), Arc::new(BreakerStore::new(flaky, threshold)))
+    }
+
+    #[tokio::test]
+    async fn opens_at_threshold_and_fails_fast() {
+        let (flaky, store) = new_store(3);
+        for _ in 0..2 {
+            assert!(store.get("key").await.is_err());
+        }
+        assert!(store.is_available());
+        assert!(store.get("key").await.is_err());
+        assert!(!store.is_available());
+
+        // Open, the store isn't called anymore
+        assert!(store.get("key").await.is_err());
+        assert_eq!(flaky.calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn success_resets_failures() {
+        let (flaky, store) = new_store(3);
+        for _ in 0..2 {
+            assert!(store.get("key").await.is_err());
+        }
+        flaky.failing.store(false, Ordering::Relaxed);
+        assert!(store.get("key").await.is_ok());
+        flaky.failing.store(true, Ordering::Relaxed);
+        for _ in 0..2 {
+            assert!(store.get("key").await.is_err());
+        }
+        assert!(store.is_available());
+    }
+
+    #[tokio::test]
+    async fn probe_closes_once_store_answers() {
+        let (flaky, store) = new_store(1);
+        assert!(store.get("key").await.is_err());
+        assert!(!store.is_available());
+
+        BreakerStore::spawn_probe(Arc::downgrade(&store), Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!store.is_available());
+        flaky.failing.store(false, Ordering::Relaxed);
+        assert!(eventually(|| async { store.is_available() }).await);
+    }
+}
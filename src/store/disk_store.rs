@@ -0,0 +1,367 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::cache::lock::CacheLock;
+use super::{CacheStore, add_variant, get_variants_key, is_url_key, list_variants, local_locks::LocalLocks};
+
+const TMP_DIR: &str = "tmp";
+
+// First line of every file, enough to rebuild the index after a restart
+#[derive(Serialize, Deserialize)]
+struct FileHeader {
+    key: String,
+    // Unix time in seconds, None for entries that live until evicted
+    expires_at: Option<u64>,
+}
+
+struct IndexEntry {
+    path: PathBuf,
+    size: u64,
+    expires_at: Option<u64>,
+    // Position in the recency list
+    tick: u64,
+}
+
+impl IndexEntry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map(|expires_at| expires_at <= now).unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DiskStats {
+    pub entries: usize,
+    pub size: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+// Where each key is on disk, least recently used files are evicted first to stay under the quota
+struct Index {
+    entries: HashMap<String, IndexEntry>,
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    quota: u64,
+    stats: DiskStats,
+}
+
+impl Index {
+    fn new(quota: u64) -> Self {
+        Index { entries: HashMap::new(), recency: BTreeMap::new(), tick: 0, quota, stats: DiskStats::default() }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, key: &str) -> Option<PathBuf> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.tick);
+        self.recency.insert(tick, key.to_string());
+        entry.tick = tick;
+        Some(entry.path.clone())
+    }
+
+    fn contains(&self, key: &str, now: u64) -> bool {
+        self.entries.get(key).map(|entry| !entry.is_expired(now)).unwrap_or(false)
+    }
+
+    fn get_expires_at(&self, key: &str) -> Option<u64> {
+        self.entries.get(key).and_then(|entry| entry.expires_at)
+    }
+
+    // Returns the files that are no longer referenced
+    fn insert(&mut self, key: &str, path: PathBuf, size: u64, expires_at: Option<u64>) -> Vec<PathBuf> {
+        let mut removed: Vec<PathBuf> = self.remove(key).into_iter().collect();
+        if size > self.quota {
+            tracing::debug!("Entry {} larger than the disk quota, not storing it", key);
+            removed.push(path);
+            return removed;
+        }
+        while self.stats.size + size > self.quota {
+            let oldest = self.recency.iter().next().map(|(_, key)| key.clone());
+            match oldest.and_then(|oldest| self.remove(&oldest)) {
+                Some(path) => {
+                    removed.push(path);
+                    self.stats.evictions += 1;
+                },
+                None => break,
+            }
+        }
+        let tick = self.next_tick();
+        self.recency.insert(tick, key.to_string());
+        self.entries.insert(key.to_string(), IndexEntry { path, size, expires_at, tick });
+        self.stats.size += size;
+        self.stats.entries = self.entries.len();
+        removed
+    }
+
+    fn remove(&mut self, key: &str) -> Option<PathBuf> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.tick);
+        self.stats.size -= entry.size;
+        self.stats.entries = self.entries.len();
+        Some(entry.path)
+    }
+
+    fn remove_matching<F>(&mut self, predicate: F) -> Vec<PathBuf>
+        where F: Fn(&str, &IndexEntry) -> bool {
+
+        let keys: Vec<String> = self.entries.iter()
+            .filter(|(key, entry)| predicate(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.iter().filter_map(|key| self.remove(key)).collect()
+    }
+
+    fn remove_expired(&mut self, now: u64) -> Vec<PathBuf> {
+        let expired = self.remove_matching(|_, entry| entry.is_expired(now));
+        self.stats.expirations += expired.len() as u64;
+        expired
+    }
+}
+
+// Entries kept as files under root/<2 first chars of the file name>/<file name>
+pub struct DiskStore {
+    root: PathBuf,
+    index: Mutex<Index>,
+    locks: LocalLocks,
+    // Variant indexes are read, updated and written back one at a time
+    variants_lock: tokio::sync::Mutex<()>,
+}
+
+impl DiskStore {
+    // Rebuild the index from the files left by a previous run
+    pub fn open(root: &str, quota: u64) -> Result<Self> {
+        let root = PathBuf::from(root);
+        let tmp = root.join(TMP_DIR);
+        // Writes that never made it to their final name
+        if tmp.exists() {
+            std::fs::remove_dir_all(&tmp)?;
+        }
+        std::fs::create_dir_all(&tmp)?;
+
+        let now = unix_now();
+        let mut files = Vec::new();
+        for shard in std::fs::read_dir(&root)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() || shard.file_name() == TMP_DIR {
+                continue;
+            }
+            for file in std::fs::read_dir(shard.path())? {
+                let file = file?;
+                let metadata = file.metadata()?;
+                match read_header(&file.path()) {
+                    Ok(header) if header.expires_at.map(|expires_at| expires_at > now).unwrap_or(true) => {
+                        files.push((metadata.modified()?, file.path(), metadata.len(), header));
+                    },
+                    _ => std::fs::remove_file(file.path())?,
+                }
+            }
+        }
+
+        // Oldest first, so the most recently written copy of a key wins and is the last to be evicted
+        files.sort_by_key(|(modified, _, _, _)| *modified);
+        let mut index = Index::new(quota);
+        for (_, path, size, header) in files {
+            for removed in index.insert(&header.key, path, size, header.expires_at) {
+                std::fs::remove_file(removed)?;
+            }
+        }
+        tracing::info!("Disk store opened with {} entries, {} bytes", index.stats.entries, index.stats.size);
+
+        Ok(DiskStore { root, index: Mutex::new(index), locks: LocalLocks::default(), variants_lock: tokio::sync::Mutex::new(()) })
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.index.lock().unwrap().entries.contains_key(key)
+    }
+
+    pub fn get_stats(&self) -> DiskStats {
+        self.index.lock().unwrap().stats
+    }
+
+    pub fn spawn_sweeper(store: Weak<DiskStore>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut last_stats = DiskStats::default();
+            loop {
+                tokio::time::sleep(interval).await;
+                let store = match store.upgrade() {
+                    Some(store) => store,
+                    None => break,
+                };
+                let expired = store.index.lock().unwrap().remove_expired(unix_now());
+                remove_files(expired).await;
+                store.locks.remove_expired(Instant::now());
+                let stats = store.get_stats();
+                if stats != last_stats {
+                    tracing::info!("Disk store: {:?}", stats);
+                    last_stats = stats;
+                }
+            }
+        });
+    }
+
+    // Write to a temporary file then rename it, readers never see a partial file even after a crash
+    async fn write(&self, key: &str, value: &str, expires_at: Option<u64>) -> Result<()> {
+        let name = Uuid::new_v4().simple().to_string();
+        let shard = self.root.join(&name[..2]);
+        let tmp_path = self.root.join(TMP_DIR).join(&name);
+        let path = shard.join(&name);
+
+        let header = serde_json::to_string(&FileHeader { key: key.to_string(), expires_at })?;
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(header.as_bytes()).await?;
+        file.write_all(b"\n").await?;
+        file.write_all(value.as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::create_dir_all(&shard).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+
+        let size = (header.len() + 1 + value.len()) as u64;
+        let removed = self.index.lock().unwrap().insert(key, path, size, expires_at);
+        remove_files(removed).await;
+        Ok(())
+    }
+
+    async fn read(&self, key: &str) -> Result<Option<String>> {
+        let expired = {
+            let mut index = self.index.lock().unwrap();
+            let expired = index.entries.get(key).map(|entry| entry.is_expired(unix_now())).unwrap_or(false);
+            if expired {
+                index.stats.expirations += 1;
+                index.remove(key)
+            } else {
+                None
+            }
+        };
+        if let Some(expired) = expired {
+            remove_files(vec![expired]).await;
+            return Ok(None);
+        }
+        let path = match self.index.lock().unwrap().get(key) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => Ok(content.split_once('\n').map(|(_, value)| value.to_string())),
+            // Evicted or replaced while we were reading it
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStore for DiskStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        self.read(key).await
+    }
+
+    // Variant index first, a stored variant is always indexed even after a crash
+    async fn put(&self, key: &str, value: &str, ttl: usize, variant: Option<(&str, &str)>) -> Result<()> {
+        let now = unix_now();
+        let expires_at = now + ttl as u64;
+        if let Some((primary_key, record)) = variant {
+            let variants_key = get_variants_key(primary_key);
+            let _guard = self.variants_lock.lock().await;
+            let variants = self.read(&variants_key).await?;
+            let (variants, variants_expires_at) = {
+                let index = self.index.lock().unwrap();
+                let variants = add_variant(variants.as_deref(), key, record, |variant| index.contains(variant, now));
+                // The index lives as long as its longest-lived variant
+                (variants, index.get_expires_at(&variants_key).map(|variants_expires_at| variants_expires_at.max(expires_at)).unwrap_or(expires_at))
+            };
+            self.write(&variants_key, &variants, Some(variants_expires_at)).await?;
+            // Still holding the lock, a concurrent put would otherwise prune this variant before its entry is written
+            return self.write(key, value, Some(expires_at)).await;
+        }
+        self.write(key, value, Some(expires_at)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let removed = self.index.lock().unwrap().remove(key);
+        remove_files(removed.into_iter().collect()).await;
+        Ok(())
+    }
+
+    async fn get_variants(&self, primary_key: &str) -> Result<Vec<String>> {
+        let index = self.read(&get_variants_key(primary_key)).await?;
+        Ok(index.map(|index| list_variants(&index)).unwrap_or_default())
+    }
+
+    async fn purge(&self, url_key: &str) -> Result<usize> {
+        let removed = self.index.lock().unwrap().remove_matching(|key, _| is_url_key(key, url_key));
+        let purged = removed.len();
+        remove_files(removed).await;
+        Ok(purged)
+    }
+
+    async fn lock(&self, cache_key: &str, ttl_ms: u64) -> Result<Option<CacheLock>> {
+        Ok(self.locks.lock(cache_key, ttl_ms))
+    }
+
+    async fn is_locked(&self, cache_key: &str) -> Result<bool> {
+        Ok(self.locks.is_locked(cache_key))
+    }
+
+    async fn unlock(&self, lock: CacheLock) -> Result<()> {
+        self.locks.unlock(lock);
+        Ok(())
+    }
+}
+
+fn read_header(path: &Path) -> Result<FileHeader> {
+    let mut line = String::new();
+    BufReader::new(std::fs::File::open(path)?).read_line(&mut line)?;
+    Ok(serde_json::from_str(line.trim_end())?)
+}
+
+async fn remove_files(paths: Vec<PathBuf>) {
+    for path in paths {
+        if let Err(err) = tokio::fs::remove_file(&path).await {
+            tracing::warn!("Unable to remove {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn survives_restart_within_quota() {
+        let root = std::env::temp_dir().join(format!("cacher-{}", Uuid::new_v4().simple()));
+        let root = root.to_str().unwrap();
+        {
+            let store = DiskStore::open(root, 400).unwrap();
+            store.put("a", &"a".repeat(100), 60, None).await.unwrap();
+            store.put("b", &"b".repeat(100), 60, Some(("b", "{}"))).await.unwrap();
+            // Over quota, "a" is the least recently used
+            store.put("c", &"c".repeat(100), 60, None).await.unwrap();
+            assert_eq!(store.get_stats().evictions, 1);
+        }
+
+        let store = DiskStore::open(root, 400).unwrap();
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert_eq!(store.get("b").await.unwrap(), Some("b".repeat(100)));
+        assert_eq!(store.get("c").await.unwrap(), Some("c".repeat(100)));
+        assert_eq!(store.get_variants("b").await.unwrap(), vec!["{}".to_string()]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cache::lock::CacheLock;
+
+// Origin locks for stores that are not shared with other instances
+#[derive(Default)]
+pub struct LocalLocks {
+    // Lock key -> (token, expiration)
+    locks: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl LocalLocks {
+    pub fn lock(&self, cache_key: &str, ttl_ms: u64) -> Option<CacheLock> {
+        let lock = CacheLock::new(cache_key);
+        let now = Instant::now();
+        let mut locks = self.locks.lock().unwrap();
+        match locks.get(lock.get_key()) {
+            Some((_, expires_at)) if *expires_at > now => None,
+            _ => {
+                locks.insert(lock.get_key().to_string(), (lock.get_token().to_string(), now + Duration::from_millis(ttl_ms)));
+                Some(lock)
+            },
+        }
+    }
+
+    pub fn is_locked(&self, cache_key: &str) -> bool {
+        let locks = self.locks.lock().unwrap();
+        locks.get(&CacheLock::get_lock_key(cache_key)).map(|(_, expires_at)| *expires_at > Instant::now()).unwrap_or(false)
+    }
+
+    pub fn unlock(&self, lock: CacheLock) {
+        let mut locks = self.locks.lock().unwrap();
+        // Only delete the lock if we still own it
+        match locks.get(lock.get_key()) {
+            Some((token, _)) if token == lock.get_token() => {
+                locks.remove(lock.get_key());
+            },
+            _ => tracing::warn!("Lock {} expired before being released", lock.get_key()),
+        }
+    }
+
+    pub fn remove_expired(&self, now: Instant) {
+        self.locks.lock().unwrap().retain(|_, (_, expires_at)| *expires_at > now);
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::cache::lock::CacheLock;
+use super::{CacheStore, add_variant, get_variants_key, is_url_key, list_variants, local_locks::LocalLocks};
+
+struct Entry {
+    value: String,
+    // None for entries that live until evicted
+    expires_at: Option<Instant>,
+    // Position in the recency list
+    tick: u64,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.map(|expires_at| expires_at <= now).unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryStats {
+    pub entries: usize,
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+// Least recently used entries are evicted first, bounded by the total size of keys and values
+struct Lru {
+    entries: HashMap<String, Entry>,
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    capacity: usize,
+    stats: MemoryStats,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Lru { entries: HashMap::new(), recency: BTreeMap::new(), tick: 0, capacity, stats: MemoryStats::default() }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, key: &str, now: Instant) -> Option<String> {
+        if self.entries.get(key).map(|entry| entry.is_expired(now)).unwrap_or(false) {
+            self.remove(key);
+            self.stats.expirations += 1;
+        }
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.recency.remove(&entry.tick);
+                self.recency.insert(tick, key.to_string());
+                entry.tick = tick;
+                self.stats.hits += 1;
+                Some(entry.value.clone())
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+        }
+    }
+
+    // Doesn't count as an access
+    fn peek(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|entry| entry.value.as_str())
+    }
+
+    // Stored and not expired, doesn't count as an access either
+    fn contains(&self, key: &str, now: Instant) -> bool {
+        self.entries.get(key).map(|entry| !entry.is_expired(now)).unwrap_or(false)
+    }
+
+    fn get_expires_at(&self, key: &str) -> Option<Instant> {
+        self.entries.get(key).and_then(|entry| entry.expires_at)
+    }
+
+    fn insert(&mut self, key: &str, value: &str, expires_at: Option<Instant>) {
+        self.remove(key);
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            tracing::debug!("Entry {} larger than the memory store, not storing it", key);
+            return;
+        }
+        while self.stats.size + size > self.capacity {
+            let oldest = self.recency.iter().next().map(|(_, key)| key.clone());
+            match oldest {
+                Some(oldest) => {
+                    self.remove(&oldest);
+                    self.stats.evictions += 1;
+                },
+                None => break,
+            }
+        }
+        let tick = self.next_tick();
+        self.recency.insert(tick, key.to_string());
+        self.entries.insert(key.to_string(), Entry { value: value.to_string(), expires_at, tick });
+        self.stats.size += size;
+        self.stats.entries = self.entries.len();
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.recency.remove(&entry.tick);
+                self.stats.size -= key.len() + entry.value.len();
+                self.stats.entries = self.entries.len();
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn remove_matching<F>(&mut self, predicate: F) -> usize
+        where F: Fn(&str, &Entry) -> bool {
+
+        let keys: Vec<String> = self.entries.iter()
+            .filter(|(key, entry)| predicate(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.iter().for_each(|key| { self.remove(key); });
+        keys.len()
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let expired = self.remove_matching(|_, entry| entry.is_expired(now));
+        self.stats.expirations += expired as u64;
+    }
+}
+
+// Cache kept in this process only, for single instance deployments without Redis
+pub struct MemoryStore {
+    lru: Mutex<Lru>,
+    locks: LocalLocks,
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize) -> Self {
+        MemoryStore { lru: Mutex::new(Lru::new(capacity)), locks: LocalLocks::default() }
+    }
+
+    pub fn clear(&self) {
+        self.lru.lock().unwrap().remove_matching(|_, _| true);
+    }
+
+    pub fn get_stats(&self) -> MemoryStats {
+        self.lru.lock().unwrap().stats
+    }
+
+    // Expired entries are only dropped when read or evicted, sweep them regularly so they don't push out fresh ones
+    pub fn spawn_sweeper(store: Weak<MemoryStore>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut last_stats = MemoryStats::default();
+            loop {
+                tokio::time::sleep(interval).await;
+                let store = match store.upgrade() {
+                    Some(store) => store,
+                    None => break,
+                };
+                let now = Instant::now();
+                store.lru.lock().unwrap().remove_expired(now);
+                store.locks.remove_expired(now);
+                let stats = store.get_stats();
+                if stats != last_stats {
+                    tracing::info!("Memory store: {:?}", stats);
+                    last_stats = stats;
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.lru.lock().unwrap().get(key, Instant::now()))
+    }
+
+    async fn put(&self, key: &str, value: &str, ttl: usize, variant: Option<(&str, &str)>) -> Result<()> {
+        let now = Instant::now();
+        let expires_at = now + Duration::from_secs(ttl as u64);
+        let mut lru = self.lru.lock().unwrap();
+        if let Some((primary_key, record)) = variant {
+            let variants_key = get_variants_key(primary_key);
+            let index = add_variant(lru.peek(&variants_key), key, record, |variant| lru.contains(variant, now));
+            // The index lives as long as its longest-lived variant
+            let index_expires_at = lru.get_expires_at(&variants_key).map(|index_expires_at| index_expires_at.max(expires_at)).unwrap_or(expires_at);
+            lru.insert(&variants_key, &index, Some(index_expires_at));
+        }
+        lru.insert(key, value, Some(expires_at));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.lru.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn get_variants(&self, primary_key: &str) -> Result<Vec<String>> {
+        let index = self.get(&get_variants_key(primary_key)).await?;
+        Ok(index.map(|index| list_variants(&index)).unwrap_or_default())
+    }
+
+    async fn purge(&self, url_key: &str) -> Result<usize> {
+        Ok(self.lru.lock().unwrap().remove_matching(|key, _| is_url_key(key, url_key)))
+    }
+
+    async fn lock(&self, cache_key: &str, ttl_ms: u64) -> Result<Option<CacheLock>> {
+        Ok(self.locks.lock(cache_key, ttl_ms))
+    }
+
+    async fn is_locked(&self, cache_key: &str) -> Result<bool> {
+        Ok(self.locks.is_locked(cache_key))
+    }
+
+    async fn unlock(&self, lock: CacheLock) -> Result<()> {
+        self.locks.unlock(lock);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_by_size() {
+        let now = Instant::now();
+        let mut lru = Lru::new(30);
+        lru.insert("a", "123456789", None);
+        lru.insert("b", "123456789", None);
+        lru.insert("c", "123456789", None);
+        // Reading "a" makes "b" the oldest
+        assert!(lru.get("a", now).is_some());
+        lru.insert("d", "123456789", None);
+
+        assert!(lru.get("b", now).is_none());
+        assert!(lru.get("a", now).is_some());
+        assert!(lru.get("d", now).is_some());
+        assert_eq!(lru.stats.evictions, 1);
+        assert_eq!(lru.stats.size, 30);
+    }
+
+    #[test]
+    fn drops_expired_entries() {
+        let now = Instant::now();
+        let mut lru = Lru::new(100);
+        lru.insert("fresh", "value", Some(now + Duration::from_secs(10)));
+        lru.insert("expired", "value", Some(now));
+
+        assert!(lru.get("expired", now).is_none());
+        assert!(lru.get("fresh", now).is_some());
+        assert_eq!(lru.stats.expirations, 1);
+        assert_eq!(lru.stats.entries, 1);
+    }
+
+    #[tokio::test]
+    async fn purges_only_the_url() {
+        let store = MemoryStore::new(1024);
+        for key in ["ns:GET|/a", "ns:GET|/a?b=1", "ns:GET|/a|c:x", "ns:GET|/a#gzip", "vary:ns:GET|/a", "ns:GET|/about", "ns:GET|/a-b", "vary:ns:GET|/about"] {
+            store.put(key, "value", 60, None).await.unwrap();
+        }
+        assert_eq!(store.purge("ns:GET|/a").await.unwrap(), 5);
+        assert_eq!(store.get_stats().entries, 3);
+        assert!(store.get("ns:GET|/about").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn prunes_and_expires_variant_index() {
+        let store = MemoryStore::new(1024);
+        store.put("page#gzip", "gzip", 60, Some(("page", "gzip"))).await.unwrap();
+        store.put("page#br", "br", 120, Some(("page", "br"))).await.unwrap();
+        store.delete("page#gzip").await.unwrap();
+        store.put("page#none", "none", 30, Some(("page", "none"))).await.unwrap();
+
+        assert_eq!(store.get_variants("page").await.unwrap(), vec!["br".to_string(), "none".to_string()]);
+        let lru = store.lru.lock().unwrap();
+        let index_expires_at = lru.get_expires_at(&get_variants_key("page")).unwrap();
+        assert_eq!(Some(index_expires_at), lru.get_expires_at("page#br"));
+    }
+}
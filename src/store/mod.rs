@@ -0,0 +1,113 @@
+pub mod breaker_store;
+pub mod disk_store;
+pub mod local_locks;
+pub mod memory_store;
+pub mod redis_store;
+pub mod split_store;
+pub mod tiered_store;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::cache::lock::CacheLock;
+use crate::config::{CacherConfig, StoreBackend};
+use crate::{BREAKER_PROBE_INTERVAL_MS, SWEEP_INTERVAL};
+use breaker_store::BreakerStore;
+use disk_store::DiskStore;
+use memory_store::MemoryStore;
+use redis_store::RedisStore;
+use split_store::SplitStore;
+use tiered_store::TieredStore;
+
+// Where cached responses, variant indexes and origin locks live
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>>;
+
+    // ttl in seconds, the entry is added to the variant index of its primary key (primary key, variant record) in the same write
+    async fn put(&self, key: &str, value: &str, ttl: usize, variant: Option<(&str, &str)>) -> Result<()>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    // Records of the variants stored for a primary key
+    async fn get_variants(&self, primary_key: &str) -> Result<Vec<String>>;
+
+    // Delete every entry and variant index of the URL, see is_url_key, returns how many were removed
+    async fn purge(&self, url_key: &str) -> Result<usize>;
+
+    // None if someone else holds the lock
+    async fn lock(&self, cache_key: &str, ttl_ms: u64) -> Result<Option<CacheLock>>;
+
+    async fn is_locked(&self, cache_key: &str) -> Result<bool>;
+
+    async fn unlock(&self, lock: CacheLock) -> Result<()>;
+
+    // False when requests should not wait on the store and go straight to the origin
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+pub async fn new_store(config: &CacherConfig) -> Result<Arc<dyn CacheStore>> {
+    let store: Arc<dyn CacheStore> = match config.store {
+        StoreBackend::Redis => Arc::new(RedisStore::new(config.get_redis(), Duration::from_millis(config.redis_connect_timeout_ms), Duration::from_millis(config.redis_command_timeout_ms)).await?),
+        StoreBackend::Memory => {
+            let store = Arc::new(MemoryStore::new(config.memory_store_size));
+            MemoryStore::spawn_sweeper(Arc::downgrade(&store), Duration::from_secs(SWEEP_INTERVAL));
+            store
+        },
+        StoreBackend::Tiered => {
+            let l1 = Arc::new(MemoryStore::new(config.memory_store_size));
+            MemoryStore::spawn_sweeper(Arc::downgrade(&l1), Duration::from_secs(SWEEP_INTERVAL));
+            let store = Arc::new(TieredStore::new(l1, RedisStore::new(config.get_redis(), Duration::from_millis(config.redis_connect_timeout_ms), Duration::from_millis(config.redis_command_timeout_ms)).await?, config.l1_ttl));
+            TieredStore::spawn_subscriber(Arc::downgrade(&store));
+            store
+        },
+        StoreBackend::Disk => new_disk_store(config)?,
+    };
+    // Large entries go to disk, unless everything already does
+    let store: Arc<dyn CacheStore> = match config.disk_threshold {
+        Some(threshold) if !matches!(config.store, StoreBackend::Disk) => Arc::new(SplitStore::new(store, new_disk_store(config)?, threshold)),
+        _ => store,
+    };
+    let store = Arc::new(BreakerStore::new(store, config.breaker_threshold));
+    BreakerStore::spawn_probe(Arc::downgrade(&store), Duration::from_millis(BREAKER_PROBE_INTERVAL_MS));
+    Ok(store)
+}
+
+pub fn get_variants_key(primary_key: &str) -> String {
+    format!("vary:{}", primary_key)
+}
+
+// Keys stored for a URL: the URL with any query, the headers and cookies of its route rule, its variants and their
+// indexes. Unlike a plain prefix, the key of /a doesn't take /about or /a-b with it
+fn is_url_key(key: &str, url_key: &str) -> bool {
+    let key = key.strip_prefix("vary:").unwrap_or(key);
+    match key.strip_prefix(url_key) {
+        Some(rest) => rest.is_empty() || rest.starts_with(['?', '|', '#']),
+        None => false,
+    }
+}
+
+// Variant index of stores without a hash type, a JSON object of variant key -> variant record.
+// Records of variants that are no longer stored are dropped so the index doesn't grow forever
+fn add_variant<F: Fn(&str) -> bool>(index: Option<&str>, key: &str, record: &str, is_stored: F) -> String {
+    let mut variants: BTreeMap<String, String> = index.and_then(|index| serde_json::from_str(index).ok()).unwrap_or_default();
+    variants.retain(|variant, _| is_stored(variant));
+    variants.insert(key.to_string(), record.to_string());
+    serde_json::to_string(&variants).unwrap_or_default()
+}
+
+fn list_variants(index: &str) -> Vec<String> {
+    serde_json::from_str::<BTreeMap<String, String>>(index).map(|variants| variants.into_values().collect()).unwrap_or_default()
+}
+
+fn new_disk_store(config: &CacherConfig) -> Result<Arc<DiskStore>> {
+    let store = Arc::new(DiskStore::open(&config.disk_store_path, config.disk_quota)?);
+    DiskStore::spawn_sweeper(Arc::downgrade(&store), Duration::from_secs(SWEEP_INTERVAL));
+    Ok(store)
+}
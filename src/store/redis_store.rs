@@ -0,0 +1,253 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use redis::{AsyncCommands, RedisResult, aio::MultiplexedConnection};
+
+use crate::cache::lock::CacheLock;
+use super::{CacheStore, get_variants_key, is_url_key};
+
+// Only delete the lock if we still own it, it may have expired and been taken by another replica
+const RELEASE_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    return redis.call("del", KEYS[1])
+else
+    return 0
+end
+"#;
+
+// Entry and variant record in one step, the variant index hash lives as long as its longest-lived variant
+const PUT_WITH_VARIANT_SCRIPT: &str = r#"
+redis.call("set", KEYS[1], ARGV[1], "EX", ARGV[2])
+redis.call("hset", KEYS[2], KEYS[1], ARGV[3])
+if redis.call("ttl", KEYS[2]) < tonumber(ARGV[2]) then
+    redis.call("expire", KEYS[2], ARGV[2])
+end
+return 1
+"#;
+
+// Keys scanned and deleted per round trip by a purge
+const PURGE_BATCH: usize = 500;
+
+type Reconnect = Shared<BoxFuture<'static, Result<MultiplexedConnection, String>>>;
+
+enum Connection {
+    Disconnected,
+    // Every command waiting for the connection shares the same attempt
+    Connecting(Reconnect),
+    Connected(MultiplexedConnection),
+}
+
+pub struct RedisStore {
+    redis_client: redis::Client,
+    // Shared by all requests, dropped when it breaks so the next command reconnects
+    connection: Arc<Mutex<Connection>>,
+    connect_timeout: Duration,
+    command_timeout: Duration,
+}
+
+impl RedisStore {
+    pub async fn new(redis_url: &str, connect_timeout: Duration, command_timeout: Duration) -> Result<Self> {
+        let redis_client = redis::Client::open(redis_url)?;
+        let store = RedisStore { redis_client, connection: Arc::new(Mutex::new(Connection::Disconnected)), connect_timeout, command_timeout };
+        // Requests bypass the cache until Redis is reachable
+        if let Err(err) = store.get_connection().await {
+            tracing::warn!("Unable to connect to Redis: {}", err);
+        }
+        Ok(store)
+    }
+
+    pub fn get_client(&self) -> &redis::Client {
+        &self.redis_client
+    }
+
+    async fn get_connection(&self) -> Result<MultiplexedConnection> {
+        let reconnect = {
+            let mut connection = self.connection.lock().unwrap();
+            match &*connection {
+                Connection::Connected(redis_conn) => return Ok(redis_conn.clone()),
+                Connection::Connecting(reconnect) => reconnect.clone(),
+                Connection::Disconnected => {
+                    let reconnect = self.spawn_reconnect();
+                    *connection = Connection::Connecting(reconnect.clone());
+                    reconnect
+                },
+            }
+        };
+        reconnect.await.map_err(anyhow::Error::msg)
+    }
+
+    // The attempt runs on its own, commands giving up on it don't cancel it for the others
+    fn spawn_reconnect(&self) -> Reconnect {
+        let redis_client = self.redis_client.clone();
+        let connection = self.connection.clone();
+        let connect_timeout = self.connect_timeout;
+        let attempt = tokio::spawn(async move {
+            let connected = match tokio::time::timeout(connect_timeout, redis_client.get_multiplexed_tokio_connection()).await {
+                Ok(Ok(redis_conn)) => Ok(redis_conn),
+                Ok(Err(err)) => Err(err.to_string()),
+                Err(_) => Err("Redis connection timed out".to_string()),
+            };
+            *connection.lock().unwrap() = match &connected {
+                Ok(redis_conn) => Connection::Connected(redis_conn.clone()),
+                Err(_) => Connection::Disconnected,
+            };
+            connected
+        });
+        async move { attempt.await.unwrap_or_else(|err| Err(err.to_string())) }.boxed().shared()
+    }
+
+    // Commands share the multiplexed connection, none of them can hold a worker thread or wait forever.
+    // The timeout covers getting the connection too, a command never waits for a slow reconnect longer than that
+    async fn run<T, F, Fut>(&self, command: F) -> Result<T>
+        where F: FnOnce(MultiplexedConnection) -> Fut,
+              Fut: Future<Output = RedisResult<T>> {
+
+        let result = tokio::time::timeout(self.command_timeout, async {
+            let redis_conn = self.get_connection().await?;
+            command(redis_conn).await.map_err(|err| {
+                if err.is_io_error() || err.is_connection_dropped() || err.is_connection_refusal() {
+                    let mut connection = self.connection.lock().unwrap();
+                    if matches!(*connection, Connection::Connected(_)) {
+                        *connection = Connection::Disconnected;
+                    }
+                }
+                anyhow::Error::from(err)
+            })
+        }).await;
+        result?
+    }
+
+    pub async fn publish(&self, channel: &str, message: &str) -> Result<()> {
+        self.run(|mut redis_conn| async move { redis_conn.publish(channel, message).await }).await
+    }
+}
+
+#[async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        self.run(|mut redis_conn| async move { redis_conn.get(key).await }).await
+    }
+
+    async fn put(&self, key: &str, value: &str, ttl: usize, variant: Option<(&str, &str)>) -> Result<()> {
+        match variant {
+            Some((primary_key, record)) => {
+                let script = redis::Script::new(PUT_WITH_VARIANT_SCRIPT);
+                let variants_key = get_variants_key(primary_key);
+                let _: i32 = self.run(|mut redis_conn| {
+                    let mut invocation = script.prepare_invoke();
+                    invocation.key(key).key(&variants_key).arg(value).arg(ttl).arg(record);
+                    async move { invocation.invoke_async(&mut redis_conn).await }
+                }).await?;
+                Ok(())
+            },
+            None => self.run(|mut redis_conn| async move { redis_conn.set_ex(key, value, ttl).await }).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.run(|mut redis_conn| async move { redis_conn.del(key).await }).await
+    }
+
+    async fn get_variants(&self, primary_key: &str) -> Result<Vec<String>> {
+        let variants_key = get_variants_key(primary_key);
+        self.run(|mut redis_conn| async move { redis_conn.hvals(variants_key).await }).await
+    }
+
+    // Every SCAN and DEL round trip gets its own timeout, walking a large keyspace takes longer than one command
+    async fn purge(&self, url_key: &str) -> Result<usize> {
+        let patterns = [url_key.to_string(), get_variants_key(url_key)].map(|prefix| format!("{}*", escape_pattern(&prefix)));
+        let mut keys: Vec<String> = Vec::new();
+        for pattern in patterns {
+            let mut cursor: u64 = 0;
+            loop {
+                let (next, batch): (u64, Vec<String>) = self.run(|mut redis_conn| {
+                    let mut cmd = redis::cmd("SCAN");
+                    cmd.arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(PURGE_BATCH);
+                    async move { cmd.query_async(&mut redis_conn).await }
+                }).await?;
+                keys.extend(batch.into_iter().filter(|key| is_url_key(key, url_key)));
+                cursor = next;
+                if cursor == 0 {
+                    break;
+                }
+            }
+        }
+        // SCAN may return a key more than once
+        keys.sort();
+        keys.dedup();
+        for chunk in keys.chunks(PURGE_BATCH) {
+            self.run(|mut redis_conn| async move { redis_conn.del::<_, ()>(chunk).await }).await?;
+        }
+        Ok(keys.len())
+    }
+
+    async fn lock(&self, cache_key: &str, ttl_ms: u64) -> Result<Option<CacheLock>> {
+        let lock = CacheLock::new(cache_key);
+        let acquired: Option<String> = self.run(|mut redis_conn| {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(lock.get_key()).arg(lock.get_token()).arg("NX").arg("PX").arg(ttl_ms);
+            async move { cmd.query_async(&mut redis_conn).await }
+        }).await?;
+        Ok(acquired.map(|_| lock))
+    }
+
+    async fn is_locked(&self, cache_key: &str) -> Result<bool> {
+        let lock_key = CacheLock::get_lock_key(cache_key);
+        self.run(|mut redis_conn| async move { redis_conn.exists(lock_key).await }).await
+    }
+
+    async fn unlock(&self, lock: CacheLock) -> Result<()> {
+        let script = redis::Script::new(RELEASE_SCRIPT);
+        let released: i32 = self.run(|mut redis_conn| {
+            let mut invocation = script.prepare_invoke();
+            invocation.key(lock.get_key()).arg(lock.get_token());
+            async move { invocation.invoke_async(&mut redis_conn).await }
+        }).await?;
+        if released == 0 {
+            tracing::warn!("Lock {} expired before being released", lock.get_key());
+        }
+        Ok(())
+    }
+}
+
+// SCAN MATCH uses glob-style patterns, URLs may contain some of its special characters
+fn escape_pattern(prefix: &str) -> String {
+    let mut escaped = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unresponsive_redis_fails_every_command_within_the_timeout() {
+        // Accepts connections but never answers, the AUTH sent on connect hangs until connect_timeout
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let redis_url = format!("redis://:password@{}/", listener.local_addr().unwrap());
+        let store = RedisStore::new(&redis_url, Duration::from_millis(1000), Duration::from_millis(100)).await.unwrap();
+
+        let start = Instant::now();
+        let commands = (0..10).map(|i| {
+            let store = &store;
+            async move { store.get(&format!("key{}", i)).await }
+        });
+        let results = futures::future::join_all(commands).await;
+        assert!(results.iter().all(|result| result.is_err()));
+        // One shared reconnect, not one connect_timeout per queued command
+        assert!(start.elapsed() < Duration::from_millis(500));
+        drop(listener);
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::cache::lock::CacheLock;
+use super::{CacheStore, disk_store::DiskStore};
+
+// Large entries go to the local disk, everything else (small entries, locks) to the main store
+pub struct SplitStore {
+    store: Arc<dyn CacheStore>,
+    disk: Arc<DiskStore>,
+    // Entries larger than this many bytes go to disk
+    threshold: usize,
+}
+
+impl SplitStore {
+    pub fn new(store: Arc<dyn CacheStore>, disk: Arc<DiskStore>, threshold: usize) -> Self {
+        SplitStore { store, disk, threshold }
+    }
+}
+
+#[async_trait]
+impl CacheStore for SplitStore {
+    // The disk index is in memory, checking it first costs nothing
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        if self.disk.contains(key) {
+            if let Some(value) = self.disk.get(key).await? {
+                return Ok(Some(value));
+            }
+        }
+        self.store.get(key).await
+    }
+
+    // Only one of them keeps the key, an entry can move from one to the other when it changes size.
+    // The variant record goes along with the entry, the most recent one is selected
+    async fn put(&self, key: &str, value: &str, ttl: usize, variant: Option<(&str, &str)>) -> Result<()> {
+        if value.len() > self.threshold {
+            self.disk.put(key, value, ttl, variant).await?;
+            self.store.delete(key).await
+        } else {
+            self.store.put(key, value, ttl, variant).await?;
+            self.disk.delete(key).await
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.disk.delete(key).await?;
+        self.store.delete(key).await
+    }
+
+    async fn get_variants(&self, primary_key: &str) -> Result<Vec<String>> {
+        let mut variants = self.disk.get_variants(primary_key).await?;
+        variants.extend(self.store.get_variants(primary_key).await?);
+        Ok(variants)
+    }
+
+    async fn purge(&self, url_key: &str) -> Result<usize> {
+        Ok(self.disk.purge(url_key).await? + self.store.purge(url_key).await?)
+    }
+
+    async fn lock(&self, cache_key: &str, ttl_ms: u64) -> Result<Option<CacheLock>> {
+        self.store.lock(cache_key, ttl_ms).await
+    }
+
+    async fn is_locked(&self, cache_key: &str) -> Result<bool> {
+        self.store.is_locked(cache_key).await
+    }
+
+    async fn unlock(&self, lock: CacheLock) -> Result<()> {
+        self.store.unlock(lock).await
+    }
+}
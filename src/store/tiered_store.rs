@@ -0,0 +1,241 @@
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::cache::{freshness::Freshness, lock::CacheLock};
+use super::{CacheStore, get_variants_key, memory_store::MemoryStore, redis_store::RedisStore};
+
+const INVALIDATION_CHANNEL: &str = "cacher:invalidate";
+
+#[derive(Serialize, Deserialize)]
+enum Target {
+    Key(String),
+    Url(String),
+}
+
+// Published on every write so other instances drop their L1 copy
+#[derive(Serialize, Deserialize)]
+struct Invalidation {
+    instance: String,
+    target: Target,
+}
+
+// Only the freshness is needed to know how long an entry may stay in L1
+#[derive(Deserialize)]
+struct StoredFreshness {
+    #[serde(default)]
+    freshness: Freshness,
+}
+
+// Hot objects kept in each instance (L1) in front of the Redis store shared by all instances (L2)
+pub struct TieredStore {
+    instance: String,
+    l1: Arc<MemoryStore>,
+    l2: RedisStore,
+    // Upper bound on how long L1 keeps an entry without asking L2, in seconds
+    l1_ttl: u64,
+}
+
+impl TieredStore {
+    pub fn new(l1: Arc<MemoryStore>, l2: RedisStore, l1_ttl: u64) -> Self {
+        TieredStore { instance: Uuid::new_v4().to_string(), l1, l2, l1_ttl }
+    }
+
+    // L1 never keeps an entry past its freshness, stale entries are always read from L2
+    fn get_l1_ttl(&self, value: &str) -> u64 {
+        let remaining = serde_json::from_str::<StoredFreshness>(value)
+            .map(|stored| stored.freshness.get_remaining())
+            .unwrap_or(0);
+        remaining.min(self.l1_ttl)
+    }
+
+    async fn put_l1(&self, key: &str, value: &str) -> Result<()> {
+        let ttl = self.get_l1_ttl(value);
+        if ttl > 0 {
+            self.l1.put(key, value, ttl as usize, None).await
+        } else {
+            self.l1.delete(key).await
+        }
+    }
+
+    async fn invalidate(&self, target: Target) {
+        let invalidation = Invalidation { instance: self.instance.clone(), target };
+        let published = match serde_json::to_string(&invalidation) {
+            Ok(message) => self.l2.publish(INVALIDATION_CHANNEL, &message).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = published {
+            tracing::warn!("Unable to publish L1 invalidation: {}", err);
+        }
+    }
+
+    async fn apply(&self, invalidation: Invalidation) -> Result<()> {
+        if invalidation.instance == self.instance {
+            return Ok(());
+        }
+        match invalidation.target {
+            Target::Key(key) => self.l1.delete(&key).await,
+            Target::Url(url_key) => self.l1.purge(&url_key).await.map(|_| ()),
+        }
+    }
+
+    // Listen to invalidations from other instances, L1 is flushed whenever we may have missed some
+    pub fn spawn_subscriber(store: Weak<TieredStore>) {
+        tokio::spawn(async move {
+            loop {
+                let redis_client = match store.upgrade() {
+                    Some(store) => store.l2.get_client().clone(),
+                    None => break,
+                };
+                let subscribed = async {
+                    let mut pubsub = redis_client.get_async_connection().await?.into_pubsub();
+                    pubsub.subscribe(INVALIDATION_CHANNEL).await?;
+                    Ok::<_, anyhow::Error>(pubsub)
+                }.await;
+                let mut pubsub = match subscribed {
+                    Ok(pubsub) => pubsub,
+                    Err(err) => {
+                        tracing::warn!("Unable to subscribe to L1 invalidations: {}", err);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    },
+                };
+                match store.upgrade() {
+                    Some(store) => store.l1.clear(),
+                    None => break,
+                }
+                let mut messages = pubsub.on_message();
+                while let Some(message) = messages.next().await {
+                    let store = match store.upgrade() {
+                        Some(store) => store,
+                        None => return,
+                    };
+                    let applied = message.get_payload::<String>().map_err(anyhow::Error::from)
+                        .and_then(|payload| Ok(serde_json::from_str::<Invalidation>(&payload)?));
+                    match applied {
+                        Ok(invalidation) => { store.apply(invalidation).await.ok(); },
+                        Err(err) => tracing::warn!("Invalid L1 invalidation: {}", err),
+                    }
+                }
+                tracing::warn!("Lost L1 invalidation subscription, reconnecting");
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl CacheStore for TieredStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.l1.get(key).await? {
+            return Ok(Some(value));
+        }
+        let value = self.l2.get(key).await?;
+        if let Some(value) = value.as_deref() {
+            self.put_l1(key, value).await?;
+        }
+        Ok(value)
+    }
+
+    async fn put(&self, key: &str, value: &str, ttl: usize, variant: Option<(&str, &str)>) -> Result<()> {
+        self.l2.put(key, value, ttl, variant).await?;
+        // The next lookup reads the updated variant list from L2
+        if let Some((primary_key, _)) = variant {
+            let variants_key = get_variants_key(primary_key);
+            self.l1.delete(&variants_key).await?;
+            self.invalidate(Target::Key(variants_key)).await;
+        }
+        self.put_l1(key, value).await?;
+        self.invalidate(Target::Key(key.to_string())).await;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.l2.delete(key).await?;
+        self.l1.delete(key).await?;
+        self.invalidate(Target::Key(key.to_string())).await;
+        Ok(())
+    }
+
+    // L1 keeps the list of variants as a plain entry
+    async fn get_variants(&self, primary_key: &str) -> Result<Vec<String>> {
+        let variants_key = get_variants_key(primary_key);
+        if let Some(variants) = self.l1.get(&variants_key).await? {
+            return Ok(serde_json::from_str(&variants)?);
+        }
+        let variants = self.l2.get_variants(primary_key).await?;
+        self.l1.put(&variants_key, &serde_json::to_string(&variants)?, self.l1_ttl as usize, None).await?;
+        Ok(variants)
+    }
+
+    async fn purge(&self, url_key: &str) -> Result<usize> {
+        let purged = self.l2.purge(url_key).await?;
+        self.l1.purge(url_key).await?;
+        self.invalidate(Target::Url(url_key.to_string())).await;
+        Ok(purged)
+    }
+
+    // Locks coordinate all instances, they only live in L2
+    async fn lock(&self, cache_key: &str, ttl_ms: u64) -> Result<Option<CacheLock>> {
+        self.l2.lock(cache_key, ttl_ms).await
+    }
+
+    async fn is_locked(&self, cache_key: &str) -> Result<bool> {
+        self.l2.is_locked(cache_key).await
+    }
+
+    async fn unlock(&self, lock: CacheLock) -> Result<()> {
+        self.l2.unlock(lock).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Response;
+
+    use crate::proxy_response::response::ProxyResponse;
+    use super::*;
+
+    // Nothing listens on port 1, these tests never reach L2
+    async fn new_store(l1_ttl: u64) -> TieredStore {
+        let l2 = RedisStore::new("redis://127.0.0.1:1/", Duration::from_millis(100), Duration::from_millis(100)).await.unwrap();
+        TieredStore::new(Arc::new(MemoryStore::new(1024 * 1024)), l2, l1_ttl)
+    }
+
+    fn stored_response(initial_age: u64, lifetime: u64) -> String {
+        let (parts, _) = Response::builder().body(()).unwrap().into_parts();
+        let mut response = ProxyResponse::from_parts(&parts);
+        let stored_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        response.freshness = Freshness { stored_at, initial_age, lifetime };
+        serde_json::to_string(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn l1_never_outlives_freshness() {
+        let store = new_store(60).await;
+        assert!(store.get_l1_ttl(&stored_response(10, 30)) <= 20);
+        assert_eq!(store.get_l1_ttl(&stored_response(10, 1000)), 60);
+        assert_eq!(store.get_l1_ttl(&stored_response(50, 30)), 0);
+        assert_eq!(store.get_l1_ttl("not a response"), 0);
+
+        // Stale entries are not kept in L1 at all
+        store.put_l1("stale", &stored_response(50, 30)).await.unwrap();
+        assert_eq!(store.l1.get("stale").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn ignores_own_invalidations() {
+        let store = new_store(60).await;
+        store.l1.put("key", "value", 60, None).await.unwrap();
+
+        store.apply(Invalidation { instance: store.instance.clone(), target: Target::Key("key".to_string()) }).await.unwrap();
+        assert!(store.l1.get("key").await.unwrap().is_some());
+
+        store.apply(Invalidation { instance: "other".to_string(), target: Target::Key("key".to_string()) }).await.unwrap();
+        assert_eq!(store.l1.get("key").await.unwrap(), None);
+    }
+}